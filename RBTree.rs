@@ -1,7 +1,6 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::process::exit;
-use std::mem::take;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum Color {
@@ -37,198 +36,1254 @@ struct SavedNodeInfo {
     saved_node: Option<NodeRef>, 
     is_left_or_right_child: i32,
     deleted_node_color: Color,
+    // key comparisons made on the way down, see `count_comparison`
+    comparisons: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InsertCase {
+    UncleRed,
+    LL,
+    LR,
+    RR,
+    RL,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeleteCase {
+    SiblingRed,
+    SiblingBlackChildrenBlack,
+    NearNephewRed,
+    FarNephewRed,
+}
+
+/// Structural steps taken while rebalancing. Rotations name the node that
+/// moves down (`pivot`) and the child that takes its place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RebalanceEvent {
+    Recolor { key: i32, color: Color },
+    RotateLeft { pivot: i32, child: i32 },
+    RotateRight { pivot: i32, child: i32 },
+    InsertCase { case: InsertCase, node: i32, parent: i32, grandparent: i32 },
+    DeleteCase { case: DeleteCase, side: Side, parent: i32, sibling: i32 },
+    PhantomCreated { parent: Option<i32> },
+}
+
+impl std::fmt::Display for RebalanceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RebalanceEvent::Recolor { key, color } => write!(f, "recolor {} {}", key, *color as i32),
+            RebalanceEvent::RotateLeft { pivot, child } => write!(f, "rotate-left {} {}", pivot, child),
+            RebalanceEvent::RotateRight { pivot, child } => write!(f, "rotate-right {} {}", pivot, child),
+            RebalanceEvent::InsertCase { case, node, parent, grandparent } =>
+                write!(f, "insert-case {:?} {} {} {}", case, node, parent, grandparent),
+            RebalanceEvent::DeleteCase { case, side, parent, sibling } =>
+                write!(f, "delete-case {:?} {:?} {} {}", case, side, parent, sibling),
+            RebalanceEvent::PhantomCreated { parent: Some(parent) } => write!(f, "phantom {}", parent),
+            RebalanceEvent::PhantomCreated { parent: None } => write!(f, "phantom N"),
+        }
+    }
+}
+
+impl RebalanceEvent {
+    fn parse(text: &str) -> Option<RebalanceEvent> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let num = |i: usize| words.get(i).and_then(|w| w.parse::<i32>().ok());
+        let event = match *words.first()? {
+            "recolor" => RebalanceEvent::Recolor {
+                key: num(1)?,
+                color: match num(2)? { 0 => Color::Black, 1 => Color::Red, _ => return None },
+            },
+            "rotate-left" => RebalanceEvent::RotateLeft { pivot: num(1)?, child: num(2)? },
+            "rotate-right" => RebalanceEvent::RotateRight { pivot: num(1)?, child: num(2)? },
+            "insert-case" => RebalanceEvent::InsertCase {
+                case: match *words.get(1)? {
+                    "UncleRed" => InsertCase::UncleRed,
+                    "LL" => InsertCase::LL,
+                    "LR" => InsertCase::LR,
+                    "RR" => InsertCase::RR,
+                    "RL" => InsertCase::RL,
+                    _ => return None,
+                },
+                node: num(2)?,
+                parent: num(3)?,
+                grandparent: num(4)?,
+            },
+            "delete-case" => RebalanceEvent::DeleteCase {
+                case: match *words.get(1)? {
+                    "SiblingRed" => DeleteCase::SiblingRed,
+                    "SiblingBlackChildrenBlack" => DeleteCase::SiblingBlackChildrenBlack,
+                    "NearNephewRed" => DeleteCase::NearNephewRed,
+                    "FarNephewRed" => DeleteCase::FarNephewRed,
+                    _ => return None,
+                },
+                side: match *words.get(2)? { "Left" => Side::Left, "Right" => Side::Right, _ => return None },
+                parent: num(3)?,
+                sibling: num(4)?,
+            },
+            "phantom" => RebalanceEvent::PhantomCreated {
+                parent: if *words.get(1)? == "N" { None } else { Some(num(1)?) },
+            },
+            _ => return None,
+        };
+        if words.len() != event.to_string().split_whitespace().count() {
+            return None;
+        }
+        Some(event)
+    }
+}
+
+trait RebalanceObserver {
+    fn on_event(&mut self, event: &RebalanceEvent);
+
+    // Also gets the tree as it stands when the event is emitted; override
+    // this instead of `on_event` to look at intermediate states.
+    fn on_step(&mut self, event: &RebalanceEvent, _root: Option<&NodeRef>) {
+        self.on_event(event);
+    }
+}
+
+struct NoObserver;
+
+impl RebalanceObserver for NoObserver {
+    fn on_event(&mut self, _event: &RebalanceEvent) {}
+}
+
+impl RebalanceObserver for Vec<Box<dyn RebalanceObserver>> {
+    fn on_event(&mut self, event: &RebalanceEvent) {
+        for observer in self.iter_mut() {
+            observer.on_event(event);
+        }
+    }
+
+    fn on_step(&mut self, event: &RebalanceEvent, root: Option<&NodeRef>) {
+        for observer in self.iter_mut() {
+            observer.on_step(event, root);
+        }
+    }
+}
+
+impl RebalanceObserver for Vec<RebalanceEvent> {
+    fn on_event(&mut self, event: &RebalanceEvent) {
+        self.push(*event);
+    }
+}
+
+struct Tee<'a> {
+    first: &'a mut dyn RebalanceObserver,
+    second: &'a mut dyn RebalanceObserver,
+}
+
+impl<'a> RebalanceObserver for Tee<'a> {
+    fn on_event(&mut self, event: &RebalanceEvent) {
+        self.first.on_event(event);
+        self.second.on_event(event);
+    }
+
+    fn on_step(&mut self, event: &RebalanceEvent, root: Option<&NodeRef>) {
+        self.first.on_step(event, root);
+        self.second.on_step(event, root);
+    }
+}
+
+fn recolor(node: &NodeRef, color: Color, root: Option<&NodeRef>, observer: &mut dyn RebalanceObserver) {
+    let key = {
+        let mut node_mut = node.borrow_mut();
+        if node_mut.color == color {
+            return;
+        }
+        node_mut.color = color;
+        node_mut.value
+    };
+    observer.on_step(&RebalanceEvent::Recolor { key, color }, root);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Off = 0,
+    // insert/delete requests and the node each one touched
+    Operations = 1,
+    // every rebalancing case taken by the fixups
+    Cases = 2,
+    // the whole tree after each rotation
+    Dumps = 3,
+}
+
+trait LogSink {
+    fn log(&mut self, level: LogLevel, message: &str);
+}
+
+/// Writes one line per message to any `io::Write` (stdout, a file, a `Vec<u8>`).
+struct WriteSink<W: std::io::Write>(W);
+
+impl<W: std::io::Write> LogSink for WriteSink<W> {
+    fn log(&mut self, _level: LogLevel, message: &str) {
+        let _ = writeln!(self.0, "{}", message);
+    }
+}
+
+/// `log`-style sinks: any closure taking the level and the message.
+impl<F: FnMut(LogLevel, &str)> LogSink for F {
+    fn log(&mut self, level: LogLevel, message: &str) {
+        self(level, message)
+    }
+}
+
+struct Logger {
+    level: LogLevel,
+    sink: Box<dyn LogSink>,
+}
+
+impl Logger {
+    fn new(level: LogLevel, sink: Box<dyn LogSink>) -> Logger {
+        Logger { level, sink }
+    }
+
+    fn off() -> Logger {
+        Logger::new(LogLevel::Off, Box::new(WriteSink(std::io::sink())))
+    }
+
+    fn stdout(level: LogLevel) -> Logger {
+        Logger::new(level, Box::new(WriteSink(std::io::stdout())))
+    }
+
+    fn enabled(&self, level: LogLevel) -> bool {
+        level != LogLevel::Off && level <= self.level
+    }
+
+    fn log(&mut self, level: LogLevel, args: std::fmt::Arguments) {
+        if self.enabled(level) {
+            self.sink.log(level, &args.to_string());
+        }
+    }
+
+    fn dump(&mut self, root: Option<&NodeRef>) {
+        if self.enabled(LogLevel::Dumps) {
+            let root_value = root.map_or("N".to_string(), |r| r.borrow().value.to_string());
+            let message = format!("root={} {}", root_value, preorder_string(root));
+            self.sink.log(LogLevel::Dumps, &message);
+        }
+    }
 }
 
 fn preorder(root: Option<&NodeRef>) {
+    print!("{}", preorder_string(root));
+}
+
+/// Serializes the tree in preorder, one `value-color-p:parent` token per node
+/// followed by a space. `color` is `1` for red and `0` for black, `parent` is
+/// the parent's value or `N` for the root. An empty tree is the empty string.
+/// `parse_tree` reads this back into the same shape and colours.
+fn preorder_string(root: Option<&NodeRef>) -> String {
+    let mut out = String::new();
+    write_preorder(root, &mut out);
+    out
+}
+
+fn write_preorder(root: Option<&NodeRef>, out: &mut String) {
     if let Some(root_ref) = root {
         let node = root_ref.borrow();
-        print!("{}-{}", node.value, node.color as i32);
+        out.push_str(&format!("{}-{}", node.value, node.color as i32));
         match &node.parent {
-            None => print!("-p:N "),
+            None => out.push_str("-p:N "),
             Some(parent_ref) => {
                 let parent = parent_ref.borrow();
-                print!("-p:{} ", parent.value);
+                out.push_str(&format!("-p:{} ", parent.value));
             },
         }
-        preorder(node.left.as_ref());
-        preorder(node.right.as_ref());
+        write_preorder(node.left.as_ref(), out);
+        write_preorder(node.right.as_ref(), out);
     }
 }
 
-fn check_red_black_tree(root: Option<&NodeRef>) -> i32 {
-    match root {
-        None => 0, 
-        Some(root_ref) => {
-            let root_node = root_ref.borrow();
-            
-            if root_node.parent.is_none() && root_node.color == Color::Red {
-                return -1;
-            }
-            
-            // Kiểm tra tính đỏ đen của cây con trái
-            let leftroute = check_red_black_tree(root_node.left.as_ref());
-            if leftroute == -1{
-                return -1;
-            }
+#[derive(Debug, Clone, PartialEq)]
+struct ParseError {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Rebuilds a tree from the `preorder_string` format and validates it.
+/// Tokens may be split over several lines.
+fn parse_tree(text: &str) -> Result<Option<NodeRef>, ParseError> {
+    let mut positions = Vec::new();
+    let root = build_tree(text, &mut positions)?;
+    if let Err(violation) = validate(root.as_ref()) {
+        let key = *violation.path.last().unwrap();
+        let (line, column) = positions.iter()
+            .find(|(value, _, _)| *value == key)
+            .map_or((1, 1), |&(_, line, column)| (line, column));
+        return Err(ParseError { line, column, message: violation.to_string() });
+    }
+    Ok(root)
+}
+
+/// Like `parse_tree` but keeps trees that break the red-black rules, so a
+/// broken state from a bug report can be loaded as-is.
+fn parse_tree_unchecked(text: &str) -> Result<Option<NodeRef>, ParseError> {
+    build_tree(text, &mut Vec::new())
+}
+
+fn build_tree(text: &str, positions: &mut Vec<(i32, usize, usize)>) -> Result<Option<NodeRef>, ParseError> {
+    let mut root: Option<NodeRef> = None;
+    // Nút trên đường từ gốc tới nút vừa đọc
+    let mut path: Vec<NodeRef> = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        for token in line.split_whitespace() {
+            let offset = token.as_ptr() as usize - line.as_ptr() as usize;
+            let column = line[..offset].chars().count() + 1;
+            let error = |message: String| ParseError { line: line_index + 1, column, message };
 
-            // Kiểm tra tính đỏ đen của cây con phải
-            let rightroute = check_red_black_tree(root_node.right.as_ref());
-            if rightroute == -1{
-                return -1;
+            let (node_part, parent_part) = match token.find("-p:") {
+                Some(at) => (&token[..at], &token[at + 3..]),
+                None => return Err(error(format!("expected value-color-p:parent, got {:?}", token))),
+            };
+            let (value, color) = match node_part.rfind('-') {
+                Some(at) if at > 0 => (&node_part[..at], &node_part[at + 1..]),
+                _ => return Err(error(format!("missing color in {:?}", token))),
+            };
+            let value: i32 = value.parse().map_err(|_| error(format!("bad value {:?}", value)))?;
+            let color = match color {
+                "0" => Color::Black,
+                "1" => Color::Red,
+                _ => return Err(error(format!("bad color {:?}, expected 0 or 1", color))),
+            };
+            let parent = match parent_part {
+                "N" => None,
+                p => Some(p.parse::<i32>().map_err(|_| error(format!("bad parent {:?}", p)))?),
+            };
+            if positions.iter().any(|(v, _, _)| *v == value) {
+                return Err(error(format!("duplicate value {}", value)));
             }
+            positions.push((value, line_index + 1, column));
 
-            // Kiểm tra hai nút đỏ liên tiếp
-            if root_node.color == Color::Red {
-                if let Some(left) = &root_node.left {
-                    if left.borrow().color == Color::Red {
-                        return -1;
+            let node = Node::new(value, color);
+            match parent {
+                None => {
+                    if root.is_some() {
+                        return Err(error(format!("second root {}", value)));
                     }
-                }
-                if let Some(right) = &root_node.right {
-                    if right.borrow().color == Color::Red {
-                        return -1;
+                    root = Some(node.clone());
+                },
+                Some(parent_value) => {
+                    if root.is_none() {
+                        return Err(error(format!("{} comes before the root", value)));
                     }
-                }
-            }
-            
-            if leftroute != rightroute {
-                return -1;
+                    while path.last().is_some_and(|p| p.borrow().value != parent_value) {
+                        path.pop();
+                    }
+                    let parent_ref = match path.last() {
+                        Some(p) => p.clone(),
+                        None => return Err(error(format!("parent {} is not an ancestor on the current path", parent_value))),
+                    };
+                    let mut parent_node = parent_ref.borrow_mut();
+                    if value < parent_value && parent_node.left.is_none() && parent_node.right.is_none() {
+                        parent_node.left = Some(node.clone());
+                    } else if value > parent_value && parent_node.right.is_none() {
+                        parent_node.right = Some(node.clone());
+                    } else {
+                        return Err(error(format!("no free {} slot under {} for {}",
+                            if value < parent_value { "left" } else { "right" }, parent_value, value)));
+                    }
+                    node.borrow_mut().parent = Some(parent_ref.clone());
+                },
             }
-            
-            leftroute + (if root_node.color == Color::Red { 0 } else { 1 })
+            path.push(node);
         }
     }
+    Ok(root)
 }
 
-fn insert_norm_bst(
-    root: Option<&NodeRef>, 
-    parent: Option<&NodeRef>, 
-    item: i32, 
-    newnode: &mut SavedNodeInfo
-) -> Option<NodeRef> {
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    // root on the left, right subtree above it, left subtree below
+    Sideways,
+    // root on top, children spread underneath
+    TopDown,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PrettyOptions {
+    layout: Layout,
+    // colour red keys with ANSI escapes instead of an R/B suffix
+    ansi: bool,
+    // append the black height of each subtree, `?` where the sides disagree
+    black_height: bool,
+}
+
+impl PrettyOptions {
+    fn new() -> PrettyOptions {
+        use std::io::IsTerminal;
+        PrettyOptions { layout: Layout::Sideways, ansi: std::io::stdout().is_terminal(), black_height: false }
+    }
+
+    fn plain() -> PrettyOptions {
+        PrettyOptions { ansi: false, ..PrettyOptions::new() }
+    }
+}
+
+// One label of a top-down drawing
+struct TopDownCell {
+    depth: usize,
+    // column where the label starts
+    column: usize,
+    label: String,
+    width: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+impl TopDownCell {
+    fn center(&self) -> usize {
+        self.column + self.width / 2
+    }
+}
+
+/// Draws a tree with box-drawing characters; see `PrettyOptions`.
+struct Pretty<'a> {
+    root: Option<&'a NodeRef>,
+    options: PrettyOptions,
+}
+
+fn pretty(root: Option<&NodeRef>, options: PrettyOptions) -> Pretty<'_> {
+    Pretty { root, options }
+}
+
+fn subtree_black_height(root: Option<&NodeRef>) -> Option<i32> {
     match root {
-        None => {
-            let new_node = Node::new(item, Color::Red);
-            
-            if let Some(parent_ref) = parent {
-                let mut new_node_mut = new_node.borrow_mut();
-                new_node_mut.parent = Some(Rc::clone(parent_ref));
-            }
-            
-            newnode.saved_node = Some(Rc::clone(&new_node));
-            
-            Some(new_node)
-        },
+        None => Some(0),
         Some(root_ref) => {
-            let mut root_node = root_ref.borrow_mut();
-            
-            if item < root_node.value {
-                let left_result = insert_norm_bst(
-                    root_node.left.as_ref(), 
-                    root,
-                    item, 
-                    newnode
-                );
-                root_node.left = left_result;
-            } else if item > root_node.value {
-                let right_result = insert_norm_bst(
-                    root_node.right.as_ref(), 
-                    root,
-                    item, 
-                    newnode
-                );
-                root_node.right = right_result;
+            let node = root_ref.borrow();
+            let left = subtree_black_height(node.left.as_ref())?;
+            let right = subtree_black_height(node.right.as_ref())?;
+            if left != right {
+                return None;
             }
-            Some(Rc::clone(root_ref))
+            Some(left + (if node.color == Color::Red { 0 } else { 1 }))
         }
     }
 }
 
-fn red_black_tree_insertion_cover(
-    root: &mut Option<NodeRef>,
-    newnode: &mut Option<NodeRef>,
-    debug: i32,
-) -> Option<NodeRef> {
-    if newnode.is_none() || root.is_none() {
-        return std::mem::take(root);
+impl<'a> Pretty<'a> {
+    // Returns the label and its width on screen (escape codes take no room)
+    fn label(&self, node_ref: &NodeRef) -> (String, usize) {
+        let node = node_ref.borrow();
+        let mut text = if self.options.ansi {
+            node.value.to_string()
+        } else {
+            format!("{}{}", node.value, if node.color == Color::Red { "R" } else { "B" })
+        };
+        if self.options.black_height {
+            let height = subtree_black_height(Some(node_ref)).map_or("?".to_string(), |h| h.to_string());
+            text.push_str(&format!("[{}]", height));
+        }
+        let width = text.chars().count();
+        if self.options.ansi && node.color == Color::Red {
+            text = format!("\x1b[31m{}\x1b[0m", text);
+        }
+        (text, width)
     }
-    if Rc::ptr_eq(newnode.as_ref().unwrap(), root.as_ref().unwrap()) {
-        newnode.as_ref().unwrap().borrow_mut().color = Color::Black;
-        return root.clone();
+
+    fn write_sideways(&self, f: &mut std::fmt::Formatter, node_ref: &NodeRef, prefix: &str, connector: &str,
+            above: &str, below: &str) -> std::fmt::Result {
+        let node = node_ref.borrow();
+        if let Some(right) = &node.right {
+            self.write_sideways(f, right, &format!("{}{}", prefix, above), "┌── ", "    ", "│   ")?;
+        }
+        writeln!(f, "{}{}{}", prefix, connector, self.label(node_ref).0)?;
+        if let Some(left) = &node.left {
+            self.write_sideways(f, left, &format!("{}{}", prefix, below), "└── ", "│   ", "    ")?;
+        }
+        Ok(())
+    }
+
+    fn write_top_down(&self, f: &mut std::fmt::Formatter, root: &NodeRef) -> std::fmt::Result {
+        let mut cells: Vec<TopDownCell> = Vec::new();
+        let mut column = 0;
+        self.place(root, 0, &mut column, &mut cells);
+        let depth = cells.iter().map(|c| c.depth).max().unwrap_or(0);
+
+        for row in 0..=depth {
+            let mut line = String::new();
+            let mut used = 0;
+            let mut row_cells: Vec<_> = cells.iter().filter(|c| c.depth == row).collect();
+            row_cells.sort_by_key(|c| c.column);
+            for cell in &row_cells {
+                line.push_str(&" ".repeat(cell.column - used));
+                line.push_str(&cell.label);
+                used = cell.column + cell.width;
+            }
+            writeln!(f, "{}", line)?;
+
+            let mut branches: Vec<char> = vec![' '; column];
+            for cell in &row_cells {
+                let middle = cell.center();
+                let from = cell.left.map_or(middle, |i| cells[i].center());
+                let to = cell.right.map_or(middle, |i| cells[i].center());
+                for b in branches.iter_mut().take(to).skip(from) {
+                    *b = '─';
+                }
+                if from < middle {
+                    branches[from] = '┌';
+                }
+                if to > middle {
+                    branches[to] = '┐';
+                }
+                branches[middle] = match (from < middle, to > middle) {
+                    (true, true) => '┴',
+                    (true, false) => '┘',
+                    (false, true) => '└',
+                    (false, false) => ' ',
+                };
+            }
+            let branches: String = branches.into_iter().collect();
+            if !branches.trim().is_empty() {
+                writeln!(f, "{}", branches.trim_end())?;
+            }
+        }
+        Ok(())
     }
 
-    if newnode.as_ref().unwrap().borrow().parent.is_none() {
-        eprintln!("Parent of new node must exist!");
-        std::process::exit(1);
+    // Gán cột theo thứ tự giữa (in-order) để cây con trái luôn nằm bên trái
+    fn place(&self, node_ref: &NodeRef, depth: usize, column: &mut usize, cells: &mut Vec<TopDownCell>) -> usize {
+        let node = node_ref.borrow();
+        let left = node.left.as_ref().map(|l| self.place(l, depth + 1, column, cells));
+        let (label, width) = self.label(node_ref);
+        cells.push(TopDownCell { depth, column: *column, label, width, left, right: None });
+        let index = cells.len() - 1;
+        *column += width + 1;
+        let right = node.right.as_ref().map(|r| self.place(r, depth + 1, column, cells));
+        cells[index].right = right;
+        index
     }
+}
 
-    let mut parent = newnode.as_ref().unwrap().borrow().parent.clone().unwrap();
-    if parent.borrow().color == Color::Red {
-        let grandparent_ref = parent.borrow().parent.clone();
-        if grandparent_ref.is_none() {
-            eprintln!("Parent of red node must exist!");
-            exit(1);
+impl<'a> std::fmt::Display for Pretty<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.root, self.options.layout) {
+            (None, _) => writeln!(f, "(empty)"),
+            (Some(root), Layout::Sideways) => self.write_sideways(f, root, "", "", "    ", "    "),
+            (Some(root), Layout::TopDown) => self.write_top_down(f, root),
         }
-        let grandparent = grandparent_ref.unwrap();
+    }
+}
 
-        if debug > 0 {
-            println!(
-                "node={}, cha={}, ong={}",
-                newnode.as_ref().unwrap().borrow().value,
-                parent.borrow().value,
-                grandparent.borrow().value
-            );
+#[derive(Debug, Clone, Copy)]
+struct DotOptions {
+    // draw the root-to-key search path in bold
+    highlight: Option<i32>,
+    nil_leaves: bool,
+    parent_edges: bool,
+}
+
+impl DotOptions {
+    fn new() -> DotOptions {
+        DotOptions { highlight: None, nil_leaves: true, parent_edges: true }
+    }
+}
+
+/// Renders the tree as a Graphviz digraph. Parent edges follow the actual
+/// `parent` pointers, so a broken back-link shows up as a stray dashed edge.
+/// Journal shapes can be drawn with `to_dot(parse_tree_unchecked(..))`.
+fn to_dot(root: Option<&NodeRef>, options: DotOptions) -> String {
+    let mut out = String::new();
+    out.push_str("digraph rbtree {\n");
+    out.push_str("    node [shape=circle, style=filled, fontcolor=white, fontname=\"Helvetica\"];\n");
+
+    // Các nút nằm trên đường tìm kiếm khóa cần làm nổi bật
+    let mut path = Vec::new();
+    if let Some(key) = options.highlight {
+        let mut current = root.cloned();
+        while let Some(node_ref) = current {
+            let node = node_ref.borrow();
+            path.push(node.value);
+            current = if key < node.value {
+                node.left.clone()
+            } else if key > node.value {
+                node.right.clone()
+            } else {
+                None
+            };
         }
+    }
 
-        let mut uncle = None;
-        let grandparent_left = grandparent.borrow().left.clone();
-        let grandparent_right = grandparent.borrow().right.clone();
+    let mut nil_count = 0;
+    write_dot(root, &options, &path, &mut nil_count, &mut out);
+    out.push_str("}\n");
+    out
+}
 
-        if let Some(ref left) = grandparent_left {
-            if Rc::ptr_eq(&parent, left) {
-                uncle = grandparent_right.clone();
+fn write_dot(root: Option<&NodeRef>, options: &DotOptions, path: &[i32], nil_count: &mut usize, out: &mut String) {
+    let root_ref = match root {
+        None => return,
+        Some(root_ref) => root_ref,
+    };
+    let node = root_ref.borrow();
+    let on_path = path.contains(&node.value);
+    let fill = if node.color == Color::Red { "red" } else { "black" };
+    out.push_str(&format!("    \"{}\" [fillcolor={}{}];\n", node.value, fill,
+        if on_path { ", penwidth=3, color=blue" } else { "" }));
+
+    for (child, port) in [(&node.left, "sw"), (&node.right, "se")].iter() {
+        match child {
+            Some(child_ref) => {
+                let child_value = child_ref.borrow().value;
+                let bold = on_path && path.contains(&child_value);
+                out.push_str(&format!("    \"{}\":{} -> \"{}\"{};\n", node.value, port, child_value,
+                    if bold { " [penwidth=3, color=blue]" } else { "" }));
+            },
+            None if options.nil_leaves => {
+                *nil_count += 1;
+                out.push_str(&format!("    nil{} [shape=box, width=0.15, height=0.15, label=\"\", fillcolor=black];\n", nil_count));
+                out.push_str(&format!("    \"{}\":{} -> nil{};\n", node.value, port, nil_count));
+            },
+            None => {},
+        }
+    }
+
+    if options.parent_edges {
+        if let Some(parent_ref) = &node.parent {
+            out.push_str(&format!("    \"{}\" -> \"{}\" [style=dashed, color=gray, constraint=false, arrowsize=0.5];\n",
+                node.value, parent_ref.borrow().value));
+        }
+    }
+
+    write_dot(node.left.as_ref(), options, path, nil_count, out);
+    write_dot(node.right.as_ref(), options, path, nil_count, out);
+}
+
+struct TidyNode {
+    value: i32,
+    color: Color,
+    depth: usize,
+    // offset from the parent while laying out, absolute once placed
+    x: f64,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+// Minimum horizontal distance between two nodes on the same level, in node slots
+const TIDY_SEPARATION: f64 = 1.0;
+
+/// Reingold-Tilford style layout: each subtree is laid out on its own, then
+/// the two children of a node are pushed apart just far enough that their
+/// facing contours keep `TIDY_SEPARATION` on every level.
+/// Returns the node index with its left and right contours, relative to it.
+fn tidy_layout(root_ref: &NodeRef, depth: usize, nodes: &mut Vec<TidyNode>) -> (usize, Vec<f64>, Vec<f64>) {
+    let node = root_ref.borrow();
+    let left = node.left.as_ref().map(|l| tidy_layout(l, depth + 1, nodes));
+    let right = node.right.as_ref().map(|r| tidy_layout(r, depth + 1, nodes));
+
+    let mut left_contour = vec![0.0];
+    let mut right_contour = vec![0.0];
+    let (left_offset, right_offset) = match (&left, &right) {
+        (Some((_, _, left_right)), Some((_, right_left, _))) => {
+            let mut gap = TIDY_SEPARATION;
+            for (l, r) in left_right.iter().zip(right_left.iter()) {
+                gap = gap.max(l - r + TIDY_SEPARATION);
             }
-            else {
-                uncle = grandparent_left.clone();
+            (-gap / 2.0, gap / 2.0)
+        },
+        _ => (-TIDY_SEPARATION / 2.0, TIDY_SEPARATION / 2.0),
+    };
+    let levels = left.as_ref().map_or(0, |l| l.1.len()).max(right.as_ref().map_or(0, |r| r.1.len()));
+    for level in 0..levels {
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        if let Some((_, l_left, l_right)) = &left {
+            if level < l_left.len() {
+                min = min.min(l_left[level] + left_offset);
+                max = max.max(l_right[level] + left_offset);
             }
         }
-
-        if uncle.is_none() {
-            uncle = Some(Node::new(i32::MAX, Color::Black));
-            if debug > 0 {
-                println!("make fantom");
+        if let Some((_, r_left, r_right)) = &right {
+            if level < r_left.len() {
+                min = min.min(r_left[level] + right_offset);
+                max = max.max(r_right[level] + right_offset);
             }
         }
+        left_contour.push(min);
+        right_contour.push(max);
+    }
 
-        if uncle.as_ref().unwrap().borrow().color == Color::Red {
-            if debug > 0 {
-                println!(
-                    "TH chu do node={}, cha={}, ong={}",
-                    newnode.as_ref().unwrap().borrow().value,
-                    parent.borrow().value,
-                    grandparent.borrow().value
-                );
-            }
-            grandparent.borrow_mut().color = Color::Red;
-            uncle.as_ref().unwrap().borrow_mut().color = Color::Black;
-            parent.borrow_mut().color = Color::Black;
-            return red_black_tree_insertion_cover(root, &mut Some(grandparent.clone()), debug);
-        } else {
-            let is_parent_left = grandparent_left
-                .as_ref()
-                .map_or(false, |left| Rc::ptr_eq(left, &parent));
+    let left_index = left.map(|(i, _, _)| i);
+    let right_index = right.map(|(i, _, _)| i);
+    if let Some(i) = left_index {
+        nodes[i].x = left_offset;
+    }
+    if let Some(i) = right_index {
+        nodes[i].x = right_offset;
+    }
+    nodes.push(TidyNode { value: node.value, color: node.color, depth, x: 0.0, left: left_index, right: right_index });
+    (nodes.len() - 1, left_contour, right_contour)
+}
+
+fn tidy_place(nodes: &mut [TidyNode], index: usize, parent_x: f64) {
+    nodes[index].x += parent_x;
+    let x = nodes[index].x;
+    if let Some(left) = nodes[index].left {
+        tidy_place(nodes, left, x);
+    }
+    if let Some(right) = nodes[index].right {
+        tidy_place(nodes, right, x);
+    }
+}
+
+// Lays out the whole tree; `x` is in node slots, the leftmost node at 0
+fn tidy_tree(root_ref: &NodeRef) -> Vec<TidyNode> {
+    let mut nodes = Vec::new();
+    let (root_index, left_contour, _) = tidy_layout(root_ref, 0, &mut nodes);
+    let min_x = left_contour.iter().cloned().fold(0.0, f64::min);
+    tidy_place(&mut nodes, root_index, -min_x);
+    nodes
+}
+
+/// Renders the tree as a standalone SVG document using `tidy_layout`.
+fn to_svg(root: Option<&NodeRef>) -> String {
+    let radius = 14.0;
+    let slot = 2.0 * radius + 6.0;
+    let level_height = 56.0;
+    let margin = radius + 4.0;
+
+    let mut nodes = Vec::new();
+    let mut width = 2.0 * margin;
+    let mut height = 2.0 * margin;
+    if let Some(root_ref) = root {
+        nodes = tidy_tree(root_ref);
+        let max_x = nodes.iter().map(|n| n.x).fold(0.0, f64::max);
+        let depth = nodes.iter().map(|n| n.depth).max().unwrap_or(0);
+        width += max_x * slot;
+        height += depth as f64 * level_height;
+    }
+
+    let position = |n: &TidyNode| (margin + n.x * slot, margin + n.depth as f64 * level_height);
+    let mut out = String::new();
+    out.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w:.0}\" height=\"{h:.0}\" viewBox=\"0 0 {w:.0} {h:.0}\" \
+         font-family=\"Helvetica, Arial, sans-serif\" font-size=\"11\" text-anchor=\"middle\">\n",
+        w = width, h = height));
+    out.push_str("<g stroke=\"#555\" stroke-width=\"1.5\">\n");
+    for node in &nodes {
+        let (x1, y1) = position(node);
+        for child in node.left.iter().chain(node.right.iter()) {
+            let (x2, y2) = position(&nodes[*child]);
+            out.push_str(&format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\"/>\n", x1, y1, x2, y2));
+        }
+    }
+    out.push_str("</g>\n");
+    for node in &nodes {
+        let (x, y) = position(node);
+        let fill = if node.color == Color::Red { "#d62828" } else { "#1d1d1d" };
+        out.push_str(&format!(
+            "<g><circle cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"{r}\" fill=\"{fill}\"/>\
+             <text x=\"{x:.1}\" y=\"{ty:.1}\" fill=\"white\">{value}</text></g>\n",
+            x = x, y = y, r = radius, fill = fill, ty = y + 4.0, value = node.value));
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+fn write_svg(root: Option<&NodeRef>, path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::write(path, to_svg(root))
+}
+
+fn check_red_black_tree(root: Option<&NodeRef>) -> i32 {
+    validate(root).unwrap_or(-1)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Rule {
+    RedRoot,
+    RedRed { parent: i32, child: i32 },
+    BlackHeightMismatch,
+    OrderViolation { low: Option<i32>, high: Option<i32> },
+    BrokenParentLink { expected: Option<i32>, found: Option<i32> },
+    // links lead back up, found as a path longer than any valid tree allows
+    Cycle,
+}
+
+/// The first invariant violation found by `validate`, with the keys from the
+/// root down to the offending node and the black height of each of its sides.
+#[derive(Debug, Clone, PartialEq)]
+struct Violation {
+    rule: Rule,
+    path: Vec<i32>,
+    left_black_height: i32,
+    right_black_height: i32,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.rule {
+            Rule::RedRoot => write!(f, "root is red")?,
+            Rule::RedRed { parent, child } => write!(f, "red node {} has red child {}", parent, child)?,
+            Rule::BlackHeightMismatch => write!(f, "black height mismatch")?,
+            Rule::OrderViolation { low, high } => {
+                let bound = |b: &Option<i32>| b.map_or("-".to_string(), |v| v.to_string());
+                write!(f, "key out of order, expected within ({}, {})", bound(low), bound(high))?
+            },
+            Rule::BrokenParentLink { expected, found } => {
+                let key = |k: &Option<i32>| k.map_or("N".to_string(), |v| v.to_string());
+                write!(f, "parent link points to {}, expected {}", key(found), key(expected))?
+            },
+            Rule::Cycle => write!(f, "child links form a cycle")?,
+        }
+        write!(f, " at path {:?} (black height left={}, right={})",
+            self.path, self.left_black_height, self.right_black_height)
+    }
+}
+
+/// Checks every red-black rule plus key order and `parent` back-links.
+/// Returns the black height of the tree, counted the same way as
+/// `check_red_black_tree`.
+fn validate(root: Option<&NodeRef>) -> Result<i32, Violation> {
+    let black_height = validate_subtree(root, None, None, None, &mut Vec::new())?;
+    if let Some(root_ref) = root {
+        let node = root_ref.borrow();
+        if node.color == Color::Red {
+            return Err(Violation {
+                rule: Rule::RedRoot,
+                path: vec![node.value],
+                left_black_height: black_height,
+                right_black_height: black_height,
+            });
+        }
+    }
+    Ok(black_height)
+}
+
+fn validate_subtree(
+    root: Option<&NodeRef>,
+    parent: Option<&NodeRef>,
+    low: Option<i32>,
+    high: Option<i32>,
+    path: &mut Vec<i32>,
+) -> Result<i32, Violation> {
+    let root_ref = match root {
+        None => return Ok(0),
+        Some(root_ref) => root_ref,
+    };
+    let node = root_ref.borrow();
+    path.push(node.value);
+
+    // Kiểm tra cây con trái và cây con phải trước
+    let left = validate_subtree(node.left.as_ref(), Some(root_ref), low, Some(node.value), path)?;
+    let right = validate_subtree(node.right.as_ref(), Some(root_ref), Some(node.value), high, path)?;
+    let violation = |rule: Rule| Violation { rule, path: path.clone(), left_black_height: left, right_black_height: right };
+
+    let linked = match (&node.parent, parent) {
+        (None, None) => true,
+        (Some(found), Some(expected)) => Rc::ptr_eq(found, expected),
+        _ => false,
+    };
+    if !linked {
+        return Err(violation(Rule::BrokenParentLink {
+            expected: parent.map(|p| p.borrow().value),
+            found: node.parent.as_ref().map(|p| p.borrow().value),
+        }));
+    }
+
+    if low.is_some_and(|l| node.value <= l) || high.is_some_and(|h| node.value >= h) {
+        return Err(violation(Rule::OrderViolation { low, high }));
+    }
+
+    // Kiểm tra hai nút đỏ liên tiếp
+    if node.color == Color::Red {
+        for child in [&node.left, &node.right].iter().filter_map(|c| c.as_ref()) {
+            if child.borrow().color == Color::Red {
+                return Err(violation(Rule::RedRed { parent: node.value, child: child.borrow().value }));
+            }
+        }
+    }
+
+    if left != right {
+        return Err(violation(Rule::BlackHeightMismatch));
+    }
+
+    path.pop();
+    Ok(left + (if node.color == Color::Red { 0 } else { 1 }))
+}
+
+/// `validate` for trees without parent links: the red-black rules and key
+/// order, reading each node through `node` as (key, colour, left, right).
+fn validate_linked<N, F>(root: Option<N>, node: &F) -> Result<i32, Violation>
+where
+    F: Fn(&N) -> (i32, Color, Option<N>, Option<N>),
+{
+    let black_height = validate_linked_subtree(root.as_ref(), node, None, None, &mut Vec::new())?;
+    if let Some((value, Color::Red, _, _)) = root.as_ref().map(node) {
+        return Err(Violation {
+            rule: Rule::RedRoot,
+            path: vec![value],
+            left_black_height: black_height,
+            right_black_height: black_height,
+        });
+    }
+    Ok(black_height)
+}
+
+fn validate_linked_subtree<N, F>(
+    link: Option<&N>,
+    node: &F,
+    low: Option<i32>,
+    high: Option<i32>,
+    path: &mut Vec<i32>,
+) -> Result<i32, Violation>
+where
+    F: Fn(&N) -> (i32, Color, Option<N>, Option<N>),
+{
+    let (value, color, left_link, right_link) = match link {
+        None => return Ok(0),
+        Some(n) => node(n),
+    };
+    path.push(value);
+    let left = validate_linked_subtree(left_link.as_ref(), node, low, Some(value), path)?;
+    let right = validate_linked_subtree(right_link.as_ref(), node, Some(value), high, path)?;
+    let violation = |rule: Rule| Violation { rule, path: path.clone(), left_black_height: left, right_black_height: right };
+
+    if low.is_some_and(|l| value <= l) || high.is_some_and(|h| value >= h) {
+        return Err(violation(Rule::OrderViolation { low, high }));
+    }
+    if color == Color::Red {
+        for child in [&left_link, &right_link].iter().filter_map(|c| c.as_ref()) {
+            if let (child, Color::Red, _, _) = node(child) {
+                return Err(violation(Rule::RedRed { parent: value, child }));
+            }
+        }
+    }
+    if left != right {
+        return Err(violation(Rule::BlackHeightMismatch));
+    }
+    path.pop();
+    Ok(left + (if color == Color::Red { 0 } else { 1 }))
+}
+
+/// Cost counters kept by `RBTree` when built with the `stats` feature
+/// (`--cfg 'feature="stats"'`); without it none of this is compiled in.
+/// A comparison is one three-way key comparison against a node. LR, RL and
+/// the near-nephew deletion case count as one double rotation, not two
+/// single ones. A fixup iteration is one pass of the insert or delete fixup
+/// that picked a case.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct OpStats {
+    comparisons: u64,
+    single_rotations: u64,
+    double_rotations: u64,
+    recolors: u64,
+    fixup_iterations: u64,
+    phantoms: u64,
+}
+
+#[cfg(feature = "stats")]
+impl OpStats {
+    fn record(&mut self, events: &[RebalanceEvent]) {
+        let mut rotations = 0;
+        for event in events {
+            match *event {
+                RebalanceEvent::Recolor { .. } => self.recolors += 1,
+                RebalanceEvent::RotateLeft { .. } | RebalanceEvent::RotateRight { .. } => rotations += 1,
+                RebalanceEvent::PhantomCreated { .. } => self.phantoms += 1,
+                RebalanceEvent::InsertCase { case, .. } => {
+                    self.fixup_iterations += 1;
+                    if case == InsertCase::LR || case == InsertCase::RL {
+                        self.double_rotations += 1;
+                        rotations -= 2;
+                    }
+                },
+                RebalanceEvent::DeleteCase { case, .. } => match case {
+                    // Mỗi vòng lặp xóa kết thúc bằng TH2 hoặc TH4
+                    DeleteCase::SiblingBlackChildrenBlack | DeleteCase::FarNephewRed => self.fixup_iterations += 1,
+                    DeleteCase::NearNephewRed => {
+                        self.double_rotations += 1;
+                        rotations -= 2;
+                    },
+                    DeleteCase::SiblingRed => {},
+                },
+            }
+        }
+        self.single_rotations += rotations as u64;
+    }
+}
+
+#[cfg(feature = "stats")]
+impl std::fmt::Display for OpStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "comparisons       {}", self.comparisons)?;
+        writeln!(f, "single rotations  {}", self.single_rotations)?;
+        writeln!(f, "double rotations  {}", self.double_rotations)?;
+        writeln!(f, "recolors          {}", self.recolors)?;
+        writeln!(f, "fixup iterations  {}", self.fixup_iterations)?;
+        write!(f, "phantom nodes     {}", self.phantoms)
+    }
+}
+
+// Đếm vào bộ đếm của chính thao tác đó; RBTree cộng dồn vào `stats` của cây
+#[inline(always)]
+fn count_comparison(_count: &mut u64) {
+    #[cfg(feature = "stats")]
+    {
+        *_count += 1;
+    }
+}
+
+fn search(root: Option<&NodeRef>, key: i32, comparisons: &mut u64) -> Option<NodeRef> {
+    let mut current = root.cloned();
+    while let Some(node_ref) = current {
+        let next = {
+            let node = node_ref.borrow();
+            count_comparison(comparisons);
+            if key == node.value {
+                return Some(node_ref.clone());
+            }
+            if key < node.value { node.left.clone() } else { node.right.clone() }
+        };
+        current = next;
+    }
+    None
+}
+
+fn count_nodes(root: Option<&NodeRef>) -> usize {
+    match root {
+        None => 0,
+        Some(root_ref) => {
+            let node = root_ref.borrow();
+            1 + count_nodes(node.left.as_ref()) + count_nodes(node.right.as_ref())
+        }
+    }
+}
+
+// Các khóa trong đoạn [low, high] theo thứ tự tăng dần
+fn collect_range(root: Option<&NodeRef>, low: i32, high: i32, out: &mut Vec<i32>) {
+    if let Some(root_ref) = root {
+        let node = root_ref.borrow();
+        if low < node.value {
+            collect_range(node.left.as_ref(), low, high, out);
+        }
+        if low <= node.value && node.value <= high {
+            out.push(node.value);
+        }
+        if node.value < high {
+            collect_range(node.right.as_ref(), low, high, out);
+        }
+    }
+}
+
+/// Shape of a tree. Depths count edges from the root (the root is at depth 0),
+/// `height` counts nodes on the longest root-to-leaf path, and a leaf is a
+/// node without children.
+#[derive(Debug, Clone, PartialEq)]
+struct ShapeStats {
+    nodes: usize,
+    height: usize,
+    // None when the tree breaks a red-black rule
+    black_height: Option<i32>,
+    min_leaf_depth: usize,
+    max_leaf_depth: usize,
+    // depth_histogram[d] is the number of nodes at depth d
+    depth_histogram: Vec<usize>,
+    red_ratio: f64,
+    // height / (2 * log2(n + 1)); a valid red-black tree stays at or below 1
+    height_bound_ratio: f64,
+}
+
+fn shape_stats(root: Option<&NodeRef>) -> ShapeStats {
+    let mut stats = ShapeStats {
+        nodes: 0,
+        height: 0,
+        black_height: validate(root).ok(),
+        min_leaf_depth: 0,
+        max_leaf_depth: 0,
+        depth_histogram: Vec::new(),
+        red_ratio: 0.0,
+        height_bound_ratio: 0.0,
+    };
+    let mut reds = 0;
+    let mut leaf_depths = Vec::new();
+    let mut stack: Vec<(NodeRef, usize)> = root.map(|r| (r.clone(), 0)).into_iter().collect();
+    while let Some((node_ref, depth)) = stack.pop() {
+        let node = node_ref.borrow();
+        stats.nodes += 1;
+        if node.color == Color::Red {
+            reds += 1;
+        }
+        if stats.depth_histogram.len() <= depth {
+            stats.depth_histogram.resize(depth + 1, 0);
+        }
+        stats.depth_histogram[depth] += 1;
+        if node.left.is_none() && node.right.is_none() {
+            leaf_depths.push(depth);
+        }
+        for child in node.left.iter().chain(node.right.iter()) {
+            stack.push((child.clone(), depth + 1));
+        }
+    }
+    if stats.nodes > 0 {
+        stats.height = stats.depth_histogram.len();
+        stats.min_leaf_depth = *leaf_depths.iter().min().unwrap();
+        stats.max_leaf_depth = *leaf_depths.iter().max().unwrap();
+        stats.red_ratio = reds as f64 / stats.nodes as f64;
+        stats.height_bound_ratio = stats.height as f64 / (2.0 * ((stats.nodes + 1) as f64).log2());
+    }
+    stats
+}
+
+impl std::fmt::Display for ShapeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "nodes {}", self.nodes)?;
+        writeln!(f, "height {} ({:.2} of the 2*log2(n+1) bound)", self.height, self.height_bound_ratio)?;
+        writeln!(f, "black height {}", self.black_height.map_or("invalid".to_string(), |h| h.to_string()))?;
+        writeln!(f, "leaf depth {}..{}", self.min_leaf_depth, self.max_leaf_depth)?;
+        writeln!(f, "red {:.1}%", self.red_ratio * 100.0)?;
+        write!(f, "depth histogram")?;
+        for (depth, count) in self.depth_histogram.iter().enumerate() {
+            write!(f, " {}:{}", depth, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn insert_norm_bst(
+    root: Option<&NodeRef>, 
+    parent: Option<&NodeRef>, 
+    item: i32, 
+    newnode: &mut SavedNodeInfo
+) -> Option<NodeRef> {
+    match root {
+        None => {
+            let new_node = Node::new(item, Color::Red);
+            
+            if let Some(parent_ref) = parent {
+                let mut new_node_mut = new_node.borrow_mut();
+                new_node_mut.parent = Some(Rc::clone(parent_ref));
+            }
+            
+            newnode.saved_node = Some(Rc::clone(&new_node));
+            
+            Some(new_node)
+        },
+        Some(root_ref) => {
+            let mut root_node = root_ref.borrow_mut();
+            
+            count_comparison(&mut newnode.comparisons);
+            if item < root_node.value {
+                let left_result = insert_norm_bst(
+                    root_node.left.as_ref(), 
+                    root,
+                    item, 
+                    newnode
+                );
+                root_node.left = left_result;
+            } else if item > root_node.value {
+                let right_result = insert_norm_bst(
+                    root_node.right.as_ref(), 
+                    root,
+                    item, 
+                    newnode
+                );
+                root_node.right = right_result;
+            }
+            Some(Rc::clone(root_ref))
+        }
+    }
+}
+
+fn red_black_tree_insertion_cover(
+    root: &mut Option<NodeRef>,
+    newnode: &mut Option<NodeRef>,
+    log: &mut Logger,
+    observer: &mut dyn RebalanceObserver,
+) -> Option<NodeRef> {
+    if newnode.is_none() || root.is_none() {
+        return std::mem::take(root);
+    }
+    if Rc::ptr_eq(newnode.as_ref().unwrap(), root.as_ref().unwrap()) {
+        recolor(newnode.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+        return root.clone();
+    }
+
+    let mut parent = newnode.as_ref().unwrap().borrow().parent.clone().expect("Parent of new node must exist!");
+    if parent.borrow().color == Color::Red {
+        let grandparent = parent.borrow().parent.clone().expect("Parent of red node must exist!");
+
+        log.log(LogLevel::Cases, format_args!(
+            "node={}, cha={}, ong={}",
+            newnode.as_ref().unwrap().borrow().value,
+            parent.borrow().value,
+            grandparent.borrow().value
+        ));
+
+        let mut uncle = None;
+        let grandparent_left = grandparent.borrow().left.clone();
+        let grandparent_right = grandparent.borrow().right.clone();
+
+        if let Some(ref left) = grandparent_left {
+            if Rc::ptr_eq(&parent, left) {
+                uncle = grandparent_right.clone();
+            }
+            else {
+                uncle = grandparent_left.clone();
+            }
+        }
+
+        if uncle.is_none() {
+            uncle = Some(Node::new(i32::MAX, Color::Black));
+            log.log(LogLevel::Cases, format_args!("make fantom"));
+            observer.on_step(&RebalanceEvent::PhantomCreated { parent: Some(grandparent.borrow().value) }, root.as_ref());
+        }
+
+        if uncle.as_ref().unwrap().borrow().color == Color::Red {
+            log.log(LogLevel::Cases, format_args!(
+                "TH chu do node={}, cha={}, ong={}",
+                newnode.as_ref().unwrap().borrow().value,
+                parent.borrow().value,
+                grandparent.borrow().value
+            ));
+            observer.on_step(&RebalanceEvent::InsertCase {
+                case: InsertCase::UncleRed,
+                node: newnode.as_ref().unwrap().borrow().value,
+                parent: parent.borrow().value,
+                grandparent: grandparent.borrow().value,
+            }, root.as_ref());
+            recolor(&grandparent, Color::Red, root.as_ref(), observer);
+            recolor(uncle.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+            recolor(&parent, Color::Black, root.as_ref(), observer);
+            return red_black_tree_insertion_cover(root, &mut Some(grandparent.clone()), log, observer);
+        } else {
+            let is_parent_left = grandparent_left
+                .as_ref()
+                .map_or(false, |left| Rc::ptr_eq(left, &parent));
 
             if is_parent_left {
+                let mut zigzag = false;
                 if !parent.borrow().right.is_none(){
                     if Rc::ptr_eq(&newnode.as_ref().unwrap(), &parent.borrow().right.as_ref().unwrap()) {
-                        if debug > 0 {
-                            println!(
-                                "TH LR node={}, cha={}, ong={}",
-                                newnode.as_ref().unwrap().borrow().value,
-                                parent.borrow().value,
-                                grandparent.borrow().value
-                            );
-                        }
+                        zigzag = true;
+                        log.log(LogLevel::Cases, format_args!(
+                            "TH LR node={}, cha={}, ong={}",
+                            newnode.as_ref().unwrap().borrow().value,
+                            parent.borrow().value,
+                            grandparent.borrow().value
+                        ));
+                        observer.on_step(&RebalanceEvent::InsertCase {
+                            case: InsertCase::LR,
+                            node: newnode.as_ref().unwrap().borrow().value,
+                            parent: parent.borrow().value,
+                            grandparent: grandparent.borrow().value,
+                        }, root.as_ref());
                         parent.borrow_mut().right = newnode.as_ref().unwrap().borrow().left.clone();
                         if !newnode.as_ref().unwrap().borrow().left.is_none(){
                             newnode.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow_mut().parent = Some(parent.clone());
@@ -240,18 +1295,28 @@ fn red_black_tree_insertion_cover(
                         parent = temp.as_ref().unwrap().clone();
                         newnode.as_ref().unwrap().borrow_mut().parent = Some(parent.clone());
                         parent.borrow_mut().parent = Some(grandparent.clone());
+                        observer.on_step(&RebalanceEvent::RotateLeft {
+                            pivot: newnode.as_ref().unwrap().borrow().value,
+                            child: parent.borrow().value,
+                        }, root.as_ref());
                     }
                 }
-                if debug > 0 {
-                    println!(
-                        "TH LL node={}, cha={}, ong={}",
-                        newnode.as_ref().unwrap().borrow().value,
-                        parent.borrow().value,
-                        grandparent.borrow().value
-                    );
+                log.log(LogLevel::Cases, format_args!(
+                    "TH LL node={}, cha={}, ong={}",
+                    newnode.as_ref().unwrap().borrow().value,
+                    parent.borrow().value,
+                    grandparent.borrow().value
+                ));
+                if !zigzag {
+                    observer.on_step(&RebalanceEvent::InsertCase {
+                        case: InsertCase::LL,
+                        node: newnode.as_ref().unwrap().borrow().value,
+                        parent: parent.borrow().value,
+                        grandparent: grandparent.borrow().value,
+                    }, root.as_ref());
                 }
-                parent.borrow_mut().color = Color::Black;
-                grandparent.borrow_mut().color = Color::Red;
+                recolor(&parent, Color::Black, root.as_ref(), observer);
+                recolor(&grandparent, Color::Red, root.as_ref(), observer);
                 let parent_of_grandparent = grandparent.borrow().parent.clone();
                 if !parent_of_grandparent.is_none() {
                     if Rc::ptr_eq(&grandparent, &parent_of_grandparent.as_ref().unwrap().borrow().left.as_ref().unwrap()){
@@ -270,18 +1335,28 @@ fn red_black_tree_insertion_cover(
                 }
                 parent.borrow_mut().parent = parent_of_grandparent.clone();
                 parent.borrow_mut().right = Some(grandparent.clone());
-                grandparent.borrow_mut().parent = Some(parent);
+                grandparent.borrow_mut().parent = Some(parent.clone());
+                observer.on_step(&RebalanceEvent::RotateRight {
+                    pivot: grandparent.borrow().value,
+                    child: parent.borrow().value,
+                }, root.as_ref());
             } else {
+                let mut zigzag = false;
                 if !parent.borrow().left.is_none(){
                     if Rc::ptr_eq(&newnode.as_ref().unwrap(), &parent.borrow().left.as_ref().unwrap()) {
-                        if debug > 0 {
-                            println!(
-                                "TH RL node={}, cha={}, ong={}",
-                                newnode.as_ref().unwrap().borrow().value,
-                                parent.borrow().value,
-                                grandparent.borrow().value
-                            );
-                        }
+                        zigzag = true;
+                        log.log(LogLevel::Cases, format_args!(
+                            "TH RL node={}, cha={}, ong={}",
+                            newnode.as_ref().unwrap().borrow().value,
+                            parent.borrow().value,
+                            grandparent.borrow().value
+                        ));
+                        observer.on_step(&RebalanceEvent::InsertCase {
+                            case: InsertCase::RL,
+                            node: newnode.as_ref().unwrap().borrow().value,
+                            parent: parent.borrow().value,
+                            grandparent: grandparent.borrow().value,
+                        }, root.as_ref());
                         parent.borrow_mut().left = newnode.as_ref().unwrap().borrow().right.clone();
                         if !newnode.as_ref().unwrap().borrow().right.is_none(){
                             newnode.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow_mut().parent = Some(parent.clone());
@@ -293,18 +1368,28 @@ fn red_black_tree_insertion_cover(
                         parent = temp.as_ref().unwrap().clone();
                         newnode.as_ref().unwrap().borrow_mut().parent = Some(parent.clone());
                         parent.borrow_mut().parent = Some(grandparent.clone());
+                        observer.on_step(&RebalanceEvent::RotateRight {
+                            pivot: newnode.as_ref().unwrap().borrow().value,
+                            child: parent.borrow().value,
+                        }, root.as_ref());
                     }
                 }
-                if debug > 0 {
-                    println!(
-                        "TH RR node={}, cha={}, ong={}",
-                        newnode.as_ref().unwrap().borrow().value,
-                        parent.borrow().value,
-                        grandparent.borrow().value
-                    );
+                log.log(LogLevel::Cases, format_args!(
+                    "TH RR node={}, cha={}, ong={}",
+                    newnode.as_ref().unwrap().borrow().value,
+                    parent.borrow().value,
+                    grandparent.borrow().value
+                ));
+                if !zigzag {
+                    observer.on_step(&RebalanceEvent::InsertCase {
+                        case: InsertCase::RR,
+                        node: newnode.as_ref().unwrap().borrow().value,
+                        parent: parent.borrow().value,
+                        grandparent: grandparent.borrow().value,
+                    }, root.as_ref());
                 }
-                parent.borrow_mut().color = Color::Black;
-                grandparent.borrow_mut().color = Color::Red;
+                recolor(&parent, Color::Black, root.as_ref(), observer);
+                recolor(&grandparent, Color::Red, root.as_ref(), observer);
                 let parent_of_grandparent = grandparent.borrow().parent.clone();
                 if !parent_of_grandparent.is_none() {
                     if Rc::ptr_eq(&grandparent, &parent_of_grandparent.as_ref().unwrap().borrow().left.as_ref().unwrap()){
@@ -323,7 +1408,11 @@ fn red_black_tree_insertion_cover(
                 }
                 parent.borrow_mut().parent = parent_of_grandparent.clone();
                 parent.borrow_mut().left = Some(grandparent.clone());
-                grandparent.borrow_mut().parent = Some(parent);
+                grandparent.borrow_mut().parent = Some(parent.clone());
+                observer.on_step(&RebalanceEvent::RotateLeft {
+                    pivot: grandparent.borrow().value,
+                    child: parent.borrow().value,
+                }, root.as_ref());
             }
         }
 
@@ -335,33 +1424,37 @@ fn red_black_tree_insertion_cover(
     return std::mem::take(root);
 }
 
-fn insert(mut root: Option<NodeRef>, x: i32, debug: i32) -> Option<NodeRef> {
-    let mut newnode = SavedNodeInfo { saved_node: None, is_left_or_right_child: 0, deleted_node_color: Color::Black};
-    root = insert_norm_bst(root.as_ref(), None, x, &mut newnode);
-    if debug > 0 {
+// Returns false, leaving the tree as it was, when `x` is already there.
+// Adds the key comparisons it made to `comparisons`.
+fn insert(root: &mut Option<NodeRef>, x: i32, log: &mut Logger, observer: &mut dyn RebalanceObserver,
+        comparisons: &mut u64) -> bool {
+    let mut newnode = SavedNodeInfo { saved_node: None, is_left_or_right_child: 0, deleted_node_color: Color::Black, comparisons: 0 };
+    *root = insert_norm_bst(root.as_ref(), None, x, &mut newnode);
+    *comparisons += newnode.comparisons;
+    if log.enabled(LogLevel::Operations) {
         if let Some(saved_node) = &newnode.saved_node {
             let saved_node_borrow = saved_node.borrow();
-            println!("\nnutvuachen: {}", saved_node_borrow.value);
+            log.log(LogLevel::Operations, format_args!("nutvuachen: {}", saved_node_borrow.value));
 
             if let Some(parent) = &saved_node_borrow.parent {
-                println!("nutcha: {}", parent.borrow().value);
+                log.log(LogLevel::Operations, format_args!("nutcha: {}", parent.borrow().value));
 
                 if let Some(grandparent) = &parent.borrow().parent {
-                    println!("nutong: {}", grandparent.borrow().value);
+                    log.log(LogLevel::Operations, format_args!("nutong: {}", grandparent.borrow().value));
                 }
             }
         } else {
-            println!("\nkhong the chen {}", x);
+            log.log(LogLevel::Operations, format_args!("khong the chen {}", x));
         }
     }
 
-    if let Some(saved_node) = newnode.saved_node {
-        root = red_black_tree_insertion_cover(&mut root, &mut Some(saved_node), debug);
-    } else {
-        eprintln!("\nLoi, khong the chen {}", x);
-        exit(1);
+    match newnode.saved_node {
+        Some(saved_node) => {
+            *root = red_black_tree_insertion_cover(root, &mut Some(saved_node), log, observer);
+            true
+        },
+        None => false,
     }
-    return root;
 }
 
 fn find_first_right_none( 
@@ -379,7 +1472,7 @@ fn find_first_left_none(
     if root.as_ref().unwrap().borrow().left.is_none(){
         return root.cloned();
     }
-    return find_first_right_none(root.as_ref().unwrap().borrow().left.as_ref());
+    return find_first_left_none(root.as_ref().unwrap().borrow().left.as_ref());
 }
 
 fn delete_norm_bst(
@@ -397,6 +1490,7 @@ fn delete_norm_bst(
                 root_node.value
             };
             
+            count_comparison(&mut newnode.comparisons);
             if item < value{
                 let root_node = root_ref.borrow();
                 let left_result = delete_norm_bst(
@@ -443,8 +1537,7 @@ fn delete_norm_bst(
                             }
                         }
                         else {
-                            print!(" Loi nghiem trong\n");
-                            exit(1);
+                            panic!(" Loi nghiem trong");
                         }
                     }
                     newnode.saved_node = Some(Node::new(i32::MAX, Color::Black));
@@ -536,7 +1629,7 @@ fn delete_norm_bst(
                             new_root.borrow_mut().left = root.as_ref().unwrap().borrow().left.clone();
                             let new_root_left = delete_norm_bst(
                                 tmp.as_ref(), 
-                                root, 
+                                Some(&new_root), 
                                 tmp.as_ref().unwrap().borrow().value, 
                                 newnode, 
                                 successor
@@ -566,323 +1659,5020 @@ fn delete_norm_bst(
                         }
                     }
                 }
-                
+                
+            }
+        }
+    }
+    root.cloned() 
+}
+
+
+fn rotate_on_parent(
+    root: &mut Option<NodeRef>,
+    newnode: Option<NodeRef>,
+    s: &mut Option<NodeRef>,
+    lor: i32,
+    log: &mut Logger,
+    observer: &mut dyn RebalanceObserver,
+) -> Option<NodeRef> {
+    // let parent = newnode.as_ref().and_then(|node| node.borrow().parent.clone());
+    let parent = newnode.as_ref().unwrap().borrow().parent.clone();
+    let grandparent = parent.as_ref().unwrap().borrow().parent.clone();
+
+    if let Some(grandparent_node) = &grandparent {
+
+        let mut grandparent_borrow = grandparent_node.borrow_mut();
+
+        if grandparent_borrow.left.as_ref().is_some_and(|left| Rc::ptr_eq(left, parent.as_ref().unwrap())) {
+            grandparent_borrow.left = s.clone();
+        } 
+        else if grandparent_borrow.right.as_ref().is_some_and(|right| Rc::ptr_eq(right, parent.as_ref().unwrap())) {
+            grandparent_borrow.right = s.clone();
+        }
+    } 
+    else {
+        log.log(LogLevel::Cases, format_args!("grandparent is NULL"));
+        *root = s.clone();
+    }
+
+    if let (Some(parent_node), Some(s_node)) = (parent.as_ref(), s.as_ref()) {
+        if lor != 0 {
+            parent_node.borrow_mut().right = s_node.borrow().left.clone();
+            if let Some(left_node) = s_node.borrow().left.as_ref() {
+                left_node.borrow_mut().parent = parent.clone();
+            }
+            s_node.borrow_mut().left = parent.clone();
+        } 
+        else {
+            parent_node.borrow_mut().left = s_node.borrow().right.clone();
+            if let Some(right_node) = s_node.borrow().right.as_ref() {
+                right_node.borrow_mut().parent = parent.clone();
+            }
+            s_node.borrow_mut().right = parent.clone();
+        }
+    }
+
+    if let Some(s_node) = s.as_ref() {
+        s_node.borrow_mut().parent = grandparent.clone();
+    }
+    if let Some(parent_node) = parent.as_ref() {
+        parent_node.borrow_mut().parent = s.clone();
+    }
+
+    if let (Some(parent_node), Some(s_node)) = (parent.as_ref(), s.as_ref()) {
+        let pivot = parent_node.borrow().value;
+        let child = s_node.borrow().value;
+        observer.on_step(&if lor != 0 {
+            RebalanceEvent::RotateLeft { pivot, child }
+        } else {
+            RebalanceEvent::RotateRight { pivot, child }
+        }, root.as_ref());
+    }
+
+    std::mem::take(root)
+}
+
+fn red_black_tree_deletion_cover(
+    mut root: Option<NodeRef>,
+    newnode: Option<NodeRef>,
+    deleted_node_color: Color,
+    lor: i32,
+    log: &mut Logger,
+    observer: &mut dyn RebalanceObserver,
+) -> Option<NodeRef> {
+    if newnode.is_none() || root.is_none(){
+
+        return root;
+    }
+    if deleted_node_color == Color::Red{
+        return root;
+    }
+    // print!("{}",newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color as i32);
+    if Rc::ptr_eq(&newnode.as_ref().unwrap(), &root.as_ref().unwrap()) || newnode.as_ref().unwrap().borrow().color == Color::Red{
+        recolor(newnode.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+        // print!("Lan 1");
+        return root;
+    }
+    else {
+        // Loi nam trong doan nay
+        let mut s : Option<NodeRef> = None;
+        let mut cs = 0;
+        if newnode.as_ref().unwrap().borrow().value == i32::MAX{
+
+            if lor == 1{
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().right.clone();
+                cs = 1;
+            }
+            else if lor == 2{
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().left.clone();
+                cs = 2;
+            }
+            else {
+                cs -= 1;
+            }
+        }
+        else {
+            if Rc::ptr_eq(&newnode.as_ref().unwrap(), &newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.as_ref().unwrap()){
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().right.clone();
+                cs = 3;
+            }
+            else if Rc::ptr_eq(&newnode.as_ref().unwrap(), &newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().right.as_ref().unwrap()){
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.clone();
+                cs = 4;
+            }
+            else {
+                cs -= 2;
+            }
+        }
+        if cs <= 0{
+            panic!("Co loi nghiem trong xay ra cs = {}", cs);
+        }
+        if cs == 1 || cs == 3{
+            if s.as_ref().unwrap().borrow().color == Color::Red{
+                log.log(LogLevel::Cases, format_args!("NDK-TH1"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::SiblingRed,
+                    side: Side::Left,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                recolor(s.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                recolor(newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                root = rotate_on_parent(&mut root, newnode.as_ref().cloned(), &mut s, 1, log, observer);
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().as_ref().unwrap().borrow().right.clone();
+                log.dump(root.as_ref());
+            }
+            if (s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black) && 
+                    (s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black){
+                log.log(LogLevel::Cases, format_args!("NDK-TH2"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::SiblingBlackChildrenBlack,
+                    side: Side::Left,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                recolor(s.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                // print!("{}", newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value);
+                root = red_black_tree_deletion_cover(root, newnode.as_ref().unwrap().borrow().parent.clone(), deleted_node_color, lor, log, observer);
+            }
+            else {
+                if s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black{
+                    log.log(LogLevel::Cases, format_args!("NDK-TH3"));
+                    observer.on_step(&RebalanceEvent::DeleteCase {
+                        case: DeleteCase::NearNephewRed,
+                        side: Side::Left,
+                        parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                        sibling: s.as_ref().unwrap().borrow().value,
+                    }, root.as_ref());
+                    recolor(s.as_ref().unwrap().borrow().left.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                    recolor(s.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                    let new_right = s.as_ref().unwrap().borrow().left.clone();
+                    newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().right = new_right.clone();
+                    new_right.as_ref().unwrap().borrow_mut().parent = newnode.as_ref().unwrap().borrow().parent.clone();
+                    s.as_ref().unwrap().borrow_mut().left = new_right.as_ref().unwrap().borrow().right.clone();
+                    if let Some(new_right_right_ref) = &new_right.as_ref().unwrap().borrow().right.as_ref(){
+                        new_right_right_ref.borrow_mut().parent = s.clone();
+                    };
+                    new_right.as_ref().unwrap().borrow_mut().right = s.clone();
+                    s.as_ref().unwrap().borrow_mut().parent = new_right.clone();
+                    observer.on_step(&RebalanceEvent::RotateRight {
+                        pivot: s.as_ref().unwrap().borrow().value,
+                        child: new_right.as_ref().unwrap().borrow().value,
+                    }, root.as_ref());
+                    s = new_right.clone();
+                    log.dump(root.as_ref());
+                }
+
+                log.log(LogLevel::Cases, format_args!("NDK-TH4"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::FarNephewRed,
+                    side: Side::Left,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                let parent_color = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color;
+                recolor(s.as_ref().unwrap(), parent_color, root.as_ref(), observer);
+                recolor(newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                recolor(s.as_ref().unwrap().borrow().right.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+
+                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 1, log, observer);
+                log.dump(root.as_ref());
+            }
+        }
+        else{
+            if s.as_ref().unwrap().borrow().color == Color::Red{
+                log.log(LogLevel::Cases, format_args!("DK-NDK-TH1"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::SiblingRed,
+                    side: Side::Right,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                recolor(s.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                recolor(newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 0, log, observer);
+                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.clone();
+                log.dump(root.as_ref());
+            }
+            if (s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black) && 
+                    (s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black){
+                log.log(LogLevel::Cases, format_args!("DK-NDK-TH2"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::SiblingBlackChildrenBlack,
+                    side: Side::Right,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                recolor(s.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                root = red_black_tree_deletion_cover(root, newnode.as_ref().unwrap().borrow().parent.clone(), deleted_node_color, lor, log, observer);
+            }
+            else {
+                if s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black{
+                    log.log(LogLevel::Cases, format_args!("DK-NDK-TH3"));
+                    observer.on_step(&RebalanceEvent::DeleteCase {
+                        case: DeleteCase::NearNephewRed,
+                        side: Side::Right,
+                        parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                        sibling: s.as_ref().unwrap().borrow().value,
+                    }, root.as_ref());
+                    recolor(s.as_ref().unwrap().borrow().right.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                    recolor(s.as_ref().unwrap(), Color::Red, root.as_ref(), observer);
+                    let new_left = s.as_ref().unwrap().borrow().right.clone();
+                    newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().left = new_left.clone();
+                    new_left.as_ref().unwrap().borrow_mut().parent = newnode.as_ref().unwrap().borrow().parent.clone();
+                    s.as_ref().unwrap().borrow_mut().right = new_left.as_ref().unwrap().borrow().left.clone();
+                    if let Some(new_left_left_ref) = &new_left.as_ref().unwrap().borrow().left.as_ref(){
+                        new_left_left_ref.borrow_mut().parent = s.clone();
+                    };
+                    new_left.as_ref().unwrap().borrow_mut().left = s.clone();
+                    s.as_ref().unwrap().borrow_mut().parent = new_left.clone();
+                    observer.on_step(&RebalanceEvent::RotateLeft {
+                        pivot: s.as_ref().unwrap().borrow().value,
+                        child: new_left.as_ref().unwrap().borrow().value,
+                    }, root.as_ref());
+                    s = new_left.clone();
+                    log.dump(root.as_ref());
+                }
+
+                log.log(LogLevel::Cases, format_args!("DK-NDK-TH4"));
+                observer.on_step(&RebalanceEvent::DeleteCase {
+                    case: DeleteCase::FarNephewRed,
+                    side: Side::Right,
+                    parent: newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value,
+                    sibling: s.as_ref().unwrap().borrow().value,
+                }, root.as_ref());
+                let parent_color = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color;
+                recolor(s.as_ref().unwrap(), parent_color, root.as_ref(), observer);
+                recolor(newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+                recolor(s.as_ref().unwrap().borrow().left.as_ref().unwrap(), Color::Black, root.as_ref(), observer);
+
+                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 0, log, observer);
+                log.dump(root.as_ref());
+            }
+        }
+        if newnode.as_ref().unwrap().borrow().value == i32::MAX{
+            drop(newnode);
+        }
+        return root;
+    }
+    // return root;
+}
+
+// Returns false, leaving the tree as it was, when `key` is not there.
+// Adds the key comparisons it made to `comparisons`.
+fn delete(
+    root:&mut Option<NodeRef>,
+    key: i32,
+    log: &mut Logger,
+    successor: i32,
+    observer: &mut dyn RebalanceObserver,
+    comparisons: &mut u64,
+) -> bool {
+    let mut newnode = SavedNodeInfo {saved_node: None, is_left_or_right_child: 0, deleted_node_color: Color::Black, comparisons: 0};
+    newnode.saved_node = None;
+    log.log(LogLevel::Operations, format_args!("nut se xoa: {}", key));
+    *root = delete_norm_bst(root.clone().as_ref(), None, key,&mut newnode, successor);
+    *comparisons += newnode.comparisons;
+    if let Some(saved_node) = &newnode.saved_node {
+        let saved_node_borrow = saved_node.borrow();
+        if saved_node_borrow.value == i32::MAX {
+            observer.on_step(&RebalanceEvent::PhantomCreated {
+                parent: saved_node_borrow.parent.as_ref().map(|p| p.borrow().value),
+            }, root.as_ref());
+        }
+    }
+    
+    if log.enabled(LogLevel::Operations) {
+        if let Some(saved_node) = &newnode.saved_node {
+            let saved_node_borrow = saved_node.borrow();
+            if let Some(parent) = &saved_node_borrow.parent {
+                log.log(LogLevel::Operations, format_args!("nutthaythe: {} nutchacua nutthaythe:{}", saved_node_borrow.value, parent.borrow().value));
+            }
+            else {
+                log.log(LogLevel::Operations, format_args!("nutthaythe: {} nutchacua nutthaythe:NULL", saved_node_borrow.value));
+            }
+        }
+        else {
+            log.log(LogLevel::Operations, format_args!("khong the xoa nut"));
+        }
+    }
+    log.dump(root.as_ref());
+
+    match newnode.saved_node {
+        Some(saved_node) => {
+            *root = red_black_tree_deletion_cover(root.as_ref().cloned(), Some(saved_node), newnode.deleted_node_color, newnode.is_left_or_right_child, log, observer);
+            true
+        },
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operation {
+    Insert(i32),
+    Delete(i32),
+}
+
+impl Operation {
+    fn parse(text: &str) -> Option<Operation> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["insert", x] => x.parse().ok().map(Operation::Insert),
+            ["delete", x] => x.parse().ok().map(Operation::Delete),
+            _ => None,
+        }
+    }
+}
+
+impl Operation {
+    // The operation that takes the tree back to the keys it had before `self`
+    fn inverse(self) -> Operation {
+        match self {
+            Operation::Insert(x) => Operation::Delete(x),
+            Operation::Delete(x) => Operation::Insert(x),
+        }
+    }
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Operation::Insert(x) => write!(f, "insert {}", x),
+            Operation::Delete(x) => write!(f, "delete {}", x),
+        }
+    }
+}
+
+struct RBTree {
+    root: Option<NodeRef>,
+    log: Logger,
+    successor: i32,
+    // Validate the whole tree after every mutation (on by default in debug builds)
+    paranoid: bool,
+    observers: Vec<Box<dyn RebalanceObserver>>,
+    journal: Option<Journal>,
+    // a Cell so that lookups through &self are counted too
+    #[cfg(feature = "stats")]
+    stats: std::cell::Cell<OpStats>,
+}
+
+impl RBTree {
+    fn new() -> RBTree {
+        RBTree {
+            root: None,
+            log: Logger::off(),
+            successor: 0,
+            paranoid: cfg!(debug_assertions),
+            observers: Vec::new(),
+            journal: None,
+            #[cfg(feature = "stats")]
+            stats: std::cell::Cell::new(OpStats::default()),
+        }
+    }
+
+    fn from_preorder(text: &str) -> Result<RBTree, ParseError> {
+        let mut tree = RBTree::new();
+        tree.root = parse_tree(text)?;
+        Ok(tree)
+    }
+
+    fn set_successor(&mut self, successor: i32) {
+        self.successor = successor;
+    }
+
+    // Start recording every mutation; the journal begins from the current tree
+    fn start_journal(&mut self) {
+        self.journal = Some(Journal {
+            successor: self.successor,
+            initial: preorder_string(self.root.as_ref()),
+            entries: Vec::new(),
+        });
+    }
+
+    fn take_journal(&mut self) -> Option<Journal> {
+        self.journal.take()
+    }
+
+    fn to_dot(&self, options: DotOptions) -> String {
+        to_dot(self.root.as_ref(), options)
+    }
+
+    fn add_observer(&mut self, observer: Box<dyn RebalanceObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn set_logger(&mut self, log: Logger) {
+        self.log = log;
+    }
+
+    fn set_paranoid(&mut self, paranoid: bool) {
+        self.paranoid = paranoid;
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        self.find(key).is_some()
+    }
+
+    fn find(&self, key: i32) -> Option<NodeRef> {
+        let mut comparisons = 0;
+        let found = search(self.root.as_ref(), key, &mut comparisons);
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.get();
+            stats.comparisons += comparisons;
+            self.stats.set(stats);
+        }
+        found
+    }
+
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> OpStats {
+        self.stats.get()
+    }
+
+    #[cfg(feature = "stats")]
+    fn reset_stats(&mut self) {
+        self.stats.set(OpStats::default());
+    }
+
+    fn range(&self, low: i32, high: i32) -> Vec<i32> {
+        let mut keys = Vec::new();
+        collect_range(self.root.as_ref(), low, high, &mut keys);
+        keys
+    }
+
+    // All keys in ascending order
+    fn keys(&self) -> Vec<i32> {
+        self.range(i32::MIN, i32::MAX)
+    }
+
+    fn len(&self) -> usize {
+        count_nodes(self.root.as_ref())
+    }
+
+    fn shape_stats(&self) -> ShapeStats {
+        shape_stats(self.root.as_ref())
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert(&mut self, x: i32) -> bool {
+        self.apply(Operation::Insert(x))
+    }
+
+    // Returns true when `key` was there
+    fn delete(&mut self, key: i32) -> bool {
+        self.apply(Operation::Delete(key))
+    }
+
+    // Returns whether the tree changed; a duplicate insert or a missing key
+    // is still journaled, with no events
+    fn apply(&mut self, op: Operation) -> bool {
+        let before = if self.paranoid { Some(preorder_string(self.root.as_ref())) } else { None };
+        let mut events: Vec<RebalanceEvent> = Vec::new();
+        let mut no_observer = NoObserver;
+        let record_events = self.journal.is_some() || cfg!(feature = "stats");
+        let mut comparisons = 0;
+        let changed = {
+            let mut observer = Tee {
+                first: &mut self.observers,
+                second: if record_events { &mut events } else { &mut no_observer },
+            };
+            match op {
+                Operation::Insert(x) => insert(&mut self.root, x, &mut self.log, &mut observer, &mut comparisons),
+                Operation::Delete(key) => delete(&mut self.root, key, &mut self.log, self.successor, &mut observer, &mut comparisons),
+            }
+        };
+        #[cfg(feature = "stats")]
+        {
+            let mut stats = self.stats.get();
+            stats.comparisons += comparisons;
+            stats.record(&events);
+            self.stats.set(stats);
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.entries.push(JournalEntry { op, events, shape: preorder_string(self.root.as_ref()) });
+        }
+        if let Some(before) = before {
+            if let Err(violation) = validate(self.root.as_ref()) {
+                panic!("{} broke the tree: {}\ntree before {}: {}\ntree after:\n{}",
+                    op, violation, op, before, pretty(self.root.as_ref(), PrettyOptions::plain()));
+            }
+        }
+        changed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct JournalEntry {
+    op: Operation,
+    events: Vec<RebalanceEvent>,
+    // preorder dump of the tree right after `op`
+    shape: String,
+}
+
+/// Every mutation applied to an `RBTree` since `start_journal`, with the
+/// rebalancing events it produced and the resulting shape.
+///
+/// The text form is one block per operation:
+///
+/// ```text
+/// successor 0
+/// initial
+/// insert 50
+///   recolor 50 0
+/// = 50-0-p:N
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+struct Journal {
+    successor: i32,
+    // preorder dump of the tree when recording started
+    initial: String,
+    entries: Vec<JournalEntry>,
+}
+
+impl std::fmt::Display for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "successor {}", self.successor)?;
+        writeln!(f, "{}", format!("initial {}", self.initial).trim_end())?;
+        for entry in &self.entries {
+            writeln!(f, "{}", entry.op)?;
+            for event in &entry.events {
+                writeln!(f, "  {}", event)?;
+            }
+            writeln!(f, "{}", format!("= {}", entry.shape).trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl Journal {
+    fn parse(text: &str) -> Result<Journal, String> {
+        let mut journal = Journal { successor: 0, initial: String::new(), entries: Vec::new() };
+        for (number, line) in text.lines().enumerate() {
+            let error = |what: &str| format!("line {}: {}: {:?}", number + 1, what, line);
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if let Some(rest) = trimmed.strip_prefix("successor") {
+                journal.successor = rest.trim().parse().map_err(|_| error("bad successor"))?;
+            } else if let Some(rest) = trimmed.strip_prefix("initial") {
+                journal.initial = normalize_shape(rest);
+            } else if let Some(rest) = trimmed.strip_prefix('=') {
+                let entry = journal.entries.last_mut().ok_or_else(|| error("shape before any operation"))?;
+                entry.shape = normalize_shape(rest);
+            } else if line.starts_with(' ') {
+                let entry = journal.entries.last_mut().ok_or_else(|| error("event before any operation"))?;
+                entry.events.push(RebalanceEvent::parse(trimmed).ok_or_else(|| error("bad event"))?);
+            } else {
+                let op = Operation::parse(trimmed).ok_or_else(|| error("bad operation"))?;
+                journal.entries.push(JournalEntry { op, events: Vec::new(), shape: String::new() });
+            }
+        }
+        Ok(journal)
+    }
+}
+
+/// Applies `ops` to an empty tree and returns the journal of the run.
+fn record_journal(ops: &[Operation], successor: i32) -> Journal {
+    let mut tree = RBTree::new();
+    tree.set_successor(successor);
+    tree.start_journal();
+    for op in ops {
+        tree.apply(*op);
+    }
+    tree.take_journal().unwrap()
+}
+
+fn normalize_shape(shape: &str) -> String {
+    shape.split_whitespace().map(|token| format!("{} ", token)).collect()
+}
+
+#[derive(Debug)]
+enum ReplayError {
+    BadInitial(ParseError),
+    Diverged { step: usize, expected: JournalEntry, actual: JournalEntry },
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::BadInitial(error) => write!(f, "cannot rebuild the initial tree: {}", error),
+            ReplayError::Diverged { step, expected, actual } => {
+                writeln!(f, "step {} ({}) diverged", step, expected.op)?;
+                writeln!(f, "expected events {:?}", expected.events)?;
+                writeln!(f, "actual events   {:?}", actual.events)?;
+                writeln!(f, "expected shape  {}", expected.shape)?;
+                write!(f, "actual shape    {}", actual.shape)
+            },
+        }
+    }
+}
+
+/// Re-runs a journal on a fresh tree and checks that every step produces the
+/// same events, shape and colours.
+fn replay(journal: &Journal) -> Result<RBTree, ReplayError> {
+    let mut tree = RBTree::new();
+    tree.root = parse_tree_unchecked(&journal.initial).map_err(ReplayError::BadInitial)?;
+    tree.set_paranoid(false);
+    tree.set_successor(journal.successor);
+    tree.start_journal();
+    for (step, expected) in journal.entries.iter().enumerate() {
+        tree.apply(expected.op);
+        let actual = tree.journal.as_ref().unwrap().entries.last().unwrap();
+        if actual.events != expected.events || normalize_shape(&actual.shape) != normalize_shape(&expected.shape) {
+            return Err(ReplayError::Diverged { step, expected: expected.clone(), actual: actual.clone() });
+        }
+    }
+    tree.take_journal();
+    Ok(tree)
+}
+
+struct AnimationFrame {
+    op: String,
+    label: String,
+    svg: String,
+}
+
+// Chụp lại cây sau mỗi bước đổi màu / xoay để làm hoạt hình
+struct FrameRecorder {
+    frames: Vec<AnimationFrame>,
+    op: String,
+    case: String,
+}
+
+impl FrameRecorder {
+    fn push(&mut self, label: String, root: Option<&NodeRef>) {
+        self.frames.push(AnimationFrame { op: self.op.clone(), label, svg: to_svg(root) });
+    }
+}
+
+fn insert_case_label(case: InsertCase) -> &'static str {
+    match case {
+        InsertCase::UncleRed => "uncle red",
+        InsertCase::LL => "LL",
+        InsertCase::LR => "LR",
+        InsertCase::RR => "RR",
+        InsertCase::RL => "RL",
+    }
+}
+
+fn delete_case_label(case: DeleteCase) -> &'static str {
+    match case {
+        DeleteCase::SiblingRed => "sibling red",
+        DeleteCase::SiblingBlackChildrenBlack => "sibling black, nephews black",
+        DeleteCase::NearNephewRed => "near nephew red",
+        DeleteCase::FarNephewRed => "far nephew red",
+    }
+}
+
+impl RebalanceObserver for FrameRecorder {
+    fn on_event(&mut self, _event: &RebalanceEvent) {}
+
+    fn on_step(&mut self, event: &RebalanceEvent, root: Option<&NodeRef>) {
+        let color_name = |color: &Color| if *color == Color::Red { "red" } else { "black" };
+        let step = match event {
+            RebalanceEvent::InsertCase { case, node, parent, grandparent } => {
+                self.case = insert_case_label(*case).to_string();
+                format!("node {}, parent {}, grandparent {}", node, parent, grandparent)
+            },
+            RebalanceEvent::DeleteCase { case, side, parent, sibling } => {
+                let side = if *side == Side::Left { "left" } else { "right" };
+                self.case = format!("{} ({} side)", delete_case_label(*case), side);
+                format!("parent {}, sibling {}", parent, sibling)
+            },
+            RebalanceEvent::Recolor { key, color } => format!("recolor {} {}", key, color_name(color)),
+            RebalanceEvent::RotateLeft { pivot, child } => format!("rotate left at {} ({} moves up)", pivot, child),
+            RebalanceEvent::RotateRight { pivot, child } => format!("rotate right at {} ({} moves up)", pivot, child),
+            RebalanceEvent::PhantomCreated { parent } =>
+                format!("phantom NIL under {}", parent.map_or("N".to_string(), |p| p.to_string())),
+        };
+        let label = if self.case.is_empty() { step } else { format!("{}: {}", self.case, step) };
+        self.push(label, root);
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '<' => out.push_str("\\u003c"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Runs `ops` on an empty tree and renders every recolour and rotation as a
+/// frame of a self-contained HTML page (inline SVG, no external scripts).
+/// Operations that would be no-ops (duplicate insert, missing key) are shown
+/// and skipped.
+fn animation_html(ops: &[Operation], successor: i32) -> String {
+    let mut root: Option<NodeRef> = None;
+    let mut log = Logger::off();
+    let mut recorder = FrameRecorder { frames: Vec::new(), op: String::new(), case: String::new() };
+
+    for op in ops {
+        recorder.op = op.to_string();
+        recorder.case = String::new();
+        recorder.push("before".to_string(), root.as_ref());
+        let changed = match *op {
+            Operation::Insert(x) => insert(&mut root, x, &mut log, &mut recorder, &mut 0),
+            Operation::Delete(key) => delete(&mut root, key, &mut log, successor, &mut recorder, &mut 0),
+        };
+        match *op {
+            _ if changed => {},
+            Operation::Insert(_) => {
+                recorder.frames.pop();
+                recorder.push("skipped, key already present".to_string(), root.as_ref());
+                continue;
+            },
+            Operation::Delete(_) => {
+                recorder.frames.pop();
+                recorder.push("skipped, key not found".to_string(), root.as_ref());
+                continue;
+            },
+        }
+        recorder.case = String::new();
+        recorder.push("done".to_string(), root.as_ref());
+    }
+
+    let frames: Vec<String> = recorder.frames.iter()
+        .map(|f| format!("{{\"op\":{},\"label\":{},\"svg\":{}}}", json_string(&f.op), json_string(&f.label), json_string(&f.svg)))
+        .collect();
+
+    let mut html = String::new();
+    html.push_str(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Red-black tree rebalancing</title>
+<style>
+body { font-family: Helvetica, Arial, sans-serif; margin: 1.5em; }
+#controls button { font-size: 1em; margin-right: 0.3em; }
+#op { margin: 0.8em 0 0.2em; }
+#label { color: #444; min-height: 1.2em; }
+#tree { overflow: auto; border-top: 1px solid #ddd; padding-top: 1em; }
+</style>
+</head>
+<body>
+<div id="controls">
+<button id="first">&#x23EE;</button><button id="prev">&#x25C0;</button><button id="play">play</button><button id="next">&#x25B6;</button><button id="last">&#x23ED;</button>
+<span id="counter"></span>
+</div>
+<h2 id="op"></h2>
+<p id="label"></p>
+<div id="tree"></div>
+<script>
+const frames = ["#);
+    html.push_str(&frames.join(",\n"));
+    html.push_str(r#"];
+let index = 0;
+let timer = null;
+function show(i) {
+  index = Math.max(0, Math.min(frames.length - 1, i));
+  const frame = frames[index];
+  document.getElementById("op").textContent = frame ? frame.op : "(no operations)";
+  document.getElementById("label").textContent = frame ? frame.label : "";
+  document.getElementById("tree").innerHTML = frame ? frame.svg : "";
+  document.getElementById("counter").textContent = frames.length ? (index + 1) + " / " + frames.length : "";
+}
+function play() {
+  if (timer) { clearInterval(timer); timer = null; document.getElementById("play").textContent = "play"; return; }
+  document.getElementById("play").textContent = "pause";
+  timer = setInterval(function () {
+    if (index >= frames.length - 1) { play(); return; }
+    show(index + 1);
+  }, 900);
+}
+document.getElementById("first").onclick = function () { show(0); };
+document.getElementById("prev").onclick = function () { show(index - 1); };
+document.getElementById("next").onclick = function () { show(index + 1); };
+document.getElementById("last").onclick = function () { show(frames.length - 1); };
+document.getElementById("play").onclick = play;
+document.addEventListener("keydown", function (e) {
+  if (e.key === "ArrowLeft") show(index - 1);
+  if (e.key === "ArrowRight") show(index + 1);
+  if (e.key === " ") { e.preventDefault(); play(); }
+});
+show(0);
+</script>
+</body>
+</html>
+"#);
+    html
+}
+
+/// `{}` draws the tree sideways, `{:#}` top-down.
+impl std::fmt::Display for RBTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let layout = if f.alternate() { Layout::TopDown } else { Layout::Sideways };
+        write!(f, "{}", pretty(self.root.as_ref(), PrettyOptions { layout, ..PrettyOptions::new() }))
+    }
+}
+
+/// Shadow mode: an `RBTree` that mirrors every operation into a `BTreeMap`
+/// and panics as soon as the two disagree on a lookup, the length or the
+/// in-order keys. The tree only stores keys, so the map's values are `()`.
+/// Duplicate inserts and missing keys are not errors; both sides just
+/// report `false` and must agree on it.
+struct ShadowTree {
+    tree: RBTree,
+    shadow: std::collections::BTreeMap<i32, ()>,
+}
+
+impl ShadowTree {
+    fn new() -> ShadowTree {
+        ShadowTree { tree: RBTree::new(), shadow: std::collections::BTreeMap::new() }
+    }
+
+    fn with_successor(successor: i32) -> ShadowTree {
+        let mut shadow = ShadowTree::new();
+        shadow.tree.set_successor(successor);
+        shadow
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert(&mut self, x: i32) -> bool {
+        let added = self.tree.insert(x);
+        assert_eq!(self.shadow.insert(x, ()).is_none(), added, "insert {}: tree and BTreeMap disagree", x);
+        self.check_len(&format!("insert {}", x));
+        added
+    }
+
+    // Returns true when `key` was there
+    fn delete(&mut self, key: i32) -> bool {
+        let removed = self.tree.delete(key);
+        assert_eq!(self.shadow.remove(&key).is_some(), removed, "delete {}: tree and BTreeMap disagree", key);
+        self.check_len(&format!("delete {}", key));
+        removed
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        let found = self.tree.contains(key);
+        assert_eq!(found, self.shadow.contains_key(&key), "find {}: tree and BTreeMap disagree", key);
+        found
+    }
+
+    fn keys(&self) -> Vec<i32> {
+        let keys = self.tree.keys();
+        let expected: Vec<i32> = self.shadow.keys().cloned().collect();
+        assert_eq!(keys, expected, "in-order keys differ from BTreeMap");
+        keys
+    }
+
+    fn range(&self, low: i32, high: i32) -> Vec<i32> {
+        let keys = self.tree.range(low, high);
+        let expected: Vec<i32> = if low <= high { self.shadow.range(low..=high).map(|(k, _)| *k).collect() } else { Vec::new() };
+        assert_eq!(keys, expected, "range {} {} differs from BTreeMap", low, high);
+        keys
+    }
+
+    // Full comparison plus the red-black rules
+    fn verify(&self) {
+        self.keys();
+        if let Err(violation) = validate(self.tree.root.as_ref()) {
+            panic!("tree agrees with BTreeMap but breaks a red-black rule: {}", violation);
+        }
+    }
+
+    fn check_len(&self, after: &str) {
+        assert_eq!(self.tree.len(), self.shadow.len(), "length differs from BTreeMap after {}", after);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SlotNode {
+    value: i32,
+    color: Color,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+// A red-black tree has height at most 2*log2(n+1), so no search is longer than this
+const MAX_SLOT_DEPTH: usize = 2 * usize::BITS as usize;
+
+/// Red-black tree algorithms over index-linked slots, used by `SyncRBTree`.
+/// Implementors only provide the slot storage. This
+/// is a separate textbook (CLRS) implementation, not a port of `RBTree`: the
+/// insert and delete fixups pick their cases the CLRS way and do not keep
+/// `RBTree`'s rebalance log or stats.
+trait SlotTree {
+    fn value(&self, i: usize) -> i32;
+    fn color_of(&self, i: usize) -> Color;
+    fn parent(&self, i: usize) -> Option<usize>;
+    fn left(&self, i: usize) -> Option<usize>;
+    fn right(&self, i: usize) -> Option<usize>;
+    fn set_color(&mut self, i: usize, color: Color);
+    fn set_parent(&mut self, i: usize, parent: Option<usize>);
+    fn set_left(&mut self, i: usize, left: Option<usize>);
+    fn set_right(&mut self, i: usize, right: Option<usize>);
+    fn root(&self) -> Option<usize>;
+    fn set_root(&mut self, root: Option<usize>);
+    // A fresh red node without children
+    fn alloc(&mut self, value: i32, parent: Option<usize>) -> usize;
+    fn release(&mut self, i: usize);
+
+    fn color(&self, node: Option<usize>) -> Color {
+        node.map_or(Color::Black, |i| self.color_of(i))
+    }
+
+    fn find_slot(&self, key: i32) -> Option<usize> {
+        let mut current = self.root();
+        while let Some(i) = current {
+            let value = self.value(i);
+            if key == value {
+                return Some(i);
+            }
+            current = if key < value { self.left(i) } else { self.right(i) };
+        }
+        None
+    }
+
+    fn range_slots(&self, low: i32, high: i32) -> Vec<i32> {
+        let mut keys = Vec::new();
+        let mut stack = Vec::new();
+        let mut current = self.root();
+        while current.is_some() || !stack.is_empty() {
+            while let Some(i) = current {
+                stack.push(i);
+                current = if low < self.value(i) { self.left(i) } else { None };
+            }
+            let i = stack.pop().unwrap();
+            let value = self.value(i);
+            if value > high {
+                break;
+            }
+            if value >= low {
+                keys.push(value);
+            }
+            current = self.right(i);
+        }
+        keys
+    }
+
+    // Same format as `preorder_string`
+    fn preorder_slots(&self) -> String {
+        let mut out = String::new();
+        let mut stack: Vec<usize> = self.root().into_iter().collect();
+        while let Some(i) = stack.pop() {
+            let parent = self.parent(i).map_or("N".to_string(), |p| self.value(p).to_string());
+            out.push_str(&format!("{}-{}-p:{} ", self.value(i), self.color_of(i) as i32, parent));
+            stack.extend(self.right(i));
+            stack.extend(self.left(i));
+        }
+        out
+    }
+
+    /// Checks the same rules as `validate` directly on the slots and returns
+    /// the black height, counted the same way.
+    fn validate_slots(&self) -> Result<i32, Violation> {
+        let black_height = self.validate_slot_subtree(self.root(), None, None, None, &mut Vec::new())?;
+        if let Some(root) = self.root().filter(|&r| self.color_of(r) == Color::Red) {
+            return Err(Violation {
+                rule: Rule::RedRoot,
+                path: vec![self.value(root)],
+                left_black_height: black_height,
+                right_black_height: black_height,
+            });
+        }
+        Ok(black_height)
+    }
+
+    fn validate_slot_subtree(
+        &self,
+        i: Option<usize>,
+        parent: Option<usize>,
+        low: Option<i32>,
+        high: Option<i32>,
+        path: &mut Vec<i32>,
+    ) -> Result<i32, Violation> {
+        let i = match i {
+            None => return Ok(0),
+            Some(i) => i,
+        };
+        let value = self.value(i);
+        // A cycle would be longer than any valid tree
+        if path.len() > MAX_SLOT_DEPTH {
+            return Err(Violation { rule: Rule::Cycle, path: path.clone(), left_black_height: 0, right_black_height: 0 });
+        }
+        path.push(value);
+
+        let left = self.validate_slot_subtree(self.left(i), Some(i), low, Some(value), path)?;
+        let right = self.validate_slot_subtree(self.right(i), Some(i), Some(value), high, path)?;
+        let violation = |rule: Rule| Violation { rule, path: path.clone(), left_black_height: left, right_black_height: right };
+
+        if self.parent(i) != parent {
+            return Err(violation(Rule::BrokenParentLink {
+                expected: parent.map(|p| self.value(p)),
+                found: self.parent(i).map(|p| self.value(p)),
+            }));
+        }
+        if low.is_some_and(|l| value <= l) || high.is_some_and(|h| value >= h) {
+            return Err(violation(Rule::OrderViolation { low, high }));
+        }
+        if self.color_of(i) == Color::Red {
+            for child in [self.left(i), self.right(i)].iter().flatten() {
+                if self.color_of(*child) == Color::Red {
+                    return Err(violation(Rule::RedRed { parent: value, child: self.value(*child) }));
+                }
+            }
+        }
+        if left != right {
+            return Err(violation(Rule::BlackHeightMismatch));
+        }
+
+        path.pop();
+        Ok(left + (if self.color_of(i) == Color::Red { 0 } else { 1 }))
+    }
+
+    fn rotate_left(&mut self, x: usize) {
+        let y = self.right(x).expect("rotate_left needs a right child");
+        let inner = self.left(y);
+        self.set_right(x, inner);
+        if let Some(child) = inner {
+            self.set_parent(child, Some(x));
+        }
+        self.replace_child(x, Some(y));
+        self.set_left(y, Some(x));
+        self.set_parent(x, Some(y));
+    }
+
+    fn rotate_right(&mut self, x: usize) {
+        let y = self.left(x).expect("rotate_right needs a left child");
+        let inner = self.right(y);
+        self.set_left(x, inner);
+        if let Some(child) = inner {
+            self.set_parent(child, Some(x));
+        }
+        self.replace_child(x, Some(y));
+        self.set_right(y, Some(x));
+        self.set_parent(x, Some(y));
+    }
+
+    // Puts `new` where `old` hangs under its parent (or at the root)
+    fn replace_child(&mut self, old: usize, new: Option<usize>) {
+        let parent = self.parent(old);
+        match parent {
+            None => self.set_root(new),
+            Some(p) if self.left(p) == Some(old) => self.set_left(p, new),
+            Some(p) => self.set_right(p, new),
+        }
+        if let Some(n) = new {
+            self.set_parent(n, parent);
+        }
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert_slot(&mut self, x: i32) -> bool {
+        let mut parent = None;
+        let mut current = self.root();
+        while let Some(i) = current {
+            let value = self.value(i);
+            if x == value {
+                return false;
+            }
+            parent = Some(i);
+            current = if x < value { self.left(i) } else { self.right(i) };
+        }
+        let z = self.alloc(x, parent);
+        match parent {
+            None => self.set_root(Some(z)),
+            Some(p) if x < self.value(p) => self.set_left(p, Some(z)),
+            Some(p) => self.set_right(p, Some(z)),
+        }
+        self.insert_fixup(z);
+        true
+    }
+
+    fn insert_fixup(&mut self, mut z: usize) {
+        while let Some(mut p) = self.parent(z).filter(|&p| self.color_of(p) == Color::Red) {
+            // Cha đỏ nên không phải gốc, luôn có ông
+            let g = self.parent(p).unwrap();
+            let parent_is_left = self.left(g) == Some(p);
+            let uncle = if parent_is_left { self.right(g) } else { self.left(g) };
+            if self.color(uncle) == Color::Red {
+                self.set_color(p, Color::Black);
+                self.set_color(uncle.unwrap(), Color::Black);
+                self.set_color(g, Color::Red);
+                z = g;
+                continue;
+            }
+            if parent_is_left && self.right(p) == Some(z) {
+                self.rotate_left(p);
+                z = p;
+                p = self.parent(z).unwrap();
+            } else if !parent_is_left && self.left(p) == Some(z) {
+                self.rotate_right(p);
+                z = p;
+                p = self.parent(z).unwrap();
+            }
+            self.set_color(p, Color::Black);
+            self.set_color(g, Color::Red);
+            if parent_is_left { self.rotate_right(g) } else { self.rotate_left(g) }
+        }
+        let root = self.root().unwrap();
+        self.set_color(root, Color::Black);
+    }
+
+    // Returns true when `key` was there
+    fn delete_slot(&mut self, key: i32) -> bool {
+        let z = match self.find_slot(key) {
+            Some(z) => z,
+            None => return false,
+        };
+        let mut removed_color = self.color_of(z);
+        let x;
+        let x_parent;
+        if self.left(z).is_none() || self.right(z).is_none() {
+            x = self.left(z).or(self.right(z));
+            x_parent = self.parent(z);
+            self.replace_child(z, x);
+        } else {
+            // Nút thế chỗ là nút nhỏ nhất của cây con phải
+            let mut y = self.right(z).unwrap();
+            while let Some(left) = self.left(y) {
+                y = left;
+            }
+            removed_color = self.color_of(y);
+            x = self.right(y);
+            if self.parent(y) == Some(z) {
+                x_parent = Some(y);
+            } else {
+                x_parent = self.parent(y);
+                self.replace_child(y, x);
+                let right = self.right(z).unwrap();
+                self.set_right(y, Some(right));
+                self.set_parent(right, Some(y));
+            }
+            self.replace_child(z, Some(y));
+            let left = self.left(z).unwrap();
+            self.set_left(y, Some(left));
+            self.set_parent(left, Some(y));
+            let color = self.color_of(z);
+            self.set_color(y, color);
+        }
+        self.release(z);
+        if removed_color == Color::Black {
+            self.delete_fixup(x, x_parent);
+        }
+        true
+    }
+
+    // `x` carries the extra black; it may be a NIL leaf, hence the separate parent
+    fn delete_fixup(&mut self, mut x: Option<usize>, mut parent: Option<usize>) {
+        while x != self.root() && self.color(x) == Color::Black {
+            let p = parent.unwrap();
+            let x_is_left = self.left(p) == x;
+            let sibling = |tree: &Self| if x_is_left { tree.right(p) } else { tree.left(p) }.unwrap();
+            let mut w = sibling(self);
+            if self.color_of(w) == Color::Red {
+                self.set_color(w, Color::Black);
+                self.set_color(p, Color::Red);
+                if x_is_left { self.rotate_left(p) } else { self.rotate_right(p) }
+                w = sibling(self);
+            }
+            let (near, far) = if x_is_left { (self.left(w), self.right(w)) } else { (self.right(w), self.left(w)) };
+            if self.color(near) == Color::Black && self.color(far) == Color::Black {
+                self.set_color(w, Color::Red);
+                x = Some(p);
+                parent = self.parent(p);
+                continue;
+            }
+            if self.color(far) == Color::Black {
+                self.set_color(near.unwrap(), Color::Black);
+                self.set_color(w, Color::Red);
+                if x_is_left { self.rotate_right(w) } else { self.rotate_left(w) }
+                w = sibling(self);
+            }
+            let color = self.color_of(p);
+            self.set_color(w, color);
+            self.set_color(p, Color::Black);
+            let far = if x_is_left { self.right(w) } else { self.left(w) };
+            self.set_color(far.unwrap(), Color::Black);
+            if x_is_left { self.rotate_left(p) } else { self.rotate_right(p) }
+            x = self.root();
+        }
+        if let Some(i) = x {
+            self.set_color(i, Color::Black);
+        }
+    }
+}
+
+/// Red-black tree with the nodes in a `Vec`, linked by index instead of
+/// `Rc<RefCell<..>>`. It has no shared ownership or interior mutability, so
+/// it is `Send + Sync` and can live behind `Arc<RwLock<SyncRBTree>>`. The
+/// balancing is the `SlotTree` one, so the shapes can differ from `RBTree`
+/// for the same operations. `preorder_string` writes the usual format, so
+/// `parse_tree` reads it back.
+#[derive(Debug, Clone, Default)]
+struct SyncRBTree {
+    nodes: Vec<SlotNode>,
+    // slots of deleted nodes, reused by the next inserts
+    free: Vec<usize>,
+    root: Option<usize>,
+    len: usize,
+}
+
+impl SlotTree for SyncRBTree {
+    fn value(&self, i: usize) -> i32 {
+        self.nodes[i].value
+    }
+
+    fn color_of(&self, i: usize) -> Color {
+        self.nodes[i].color
+    }
+
+    fn parent(&self, i: usize) -> Option<usize> {
+        self.nodes[i].parent
+    }
+
+    fn left(&self, i: usize) -> Option<usize> {
+        self.nodes[i].left
+    }
+
+    fn right(&self, i: usize) -> Option<usize> {
+        self.nodes[i].right
+    }
+
+    fn set_color(&mut self, i: usize, color: Color) {
+        self.nodes[i].color = color;
+    }
+
+    fn set_parent(&mut self, i: usize, parent: Option<usize>) {
+        self.nodes[i].parent = parent;
+    }
+
+    fn set_left(&mut self, i: usize, left: Option<usize>) {
+        self.nodes[i].left = left;
+    }
+
+    fn set_right(&mut self, i: usize, right: Option<usize>) {
+        self.nodes[i].right = right;
+    }
+
+    fn root(&self) -> Option<usize> {
+        self.root
+    }
+
+    fn set_root(&mut self, root: Option<usize>) {
+        self.root = root;
+    }
+
+    fn alloc(&mut self, value: i32, parent: Option<usize>) -> usize {
+        let node = SlotNode { value, color: Color::Red, parent, left: None, right: None };
+        match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        }
+    }
+
+    fn release(&mut self, i: usize) {
+        self.free.push(i);
+    }
+}
+
+impl SyncRBTree {
+    fn new() -> SyncRBTree {
+        SyncRBTree::default()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        self.find_slot(key).is_some()
+    }
+
+    fn range(&self, low: i32, high: i32) -> Vec<i32> {
+        self.range_slots(low, high)
+    }
+
+    fn keys(&self) -> Vec<i32> {
+        self.range_slots(i32::MIN, i32::MAX)
+    }
+
+    fn preorder_string(&self) -> String {
+        self.preorder_slots()
+    }
+
+    // Full check of every rule, O(n)
+    fn validate(&self) -> Result<i32, Violation> {
+        self.validate_slots()
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert(&mut self, x: i32) -> bool {
+        let added = self.insert_slot(x);
+        if added {
+            self.len += 1;
+        }
+        added
+    }
+
+    // Returns true when `key` was there
+    fn delete(&mut self, key: i32) -> bool {
+        let removed = self.delete_slot(key);
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+}
+
+/// One writer and `readers` reader threads share an `Arc<RwLock<SyncRBTree>>`
+/// for `ops` random inserts and deletes. Readers validate the whole tree and
+/// check lookups against the keys they just listed; the writer checks every
+/// step against a `BTreeSet`. Finally the tree is moved to another thread and
+/// checked there, and its `preorder_string` must read back through
+/// `parse_tree`. Used by `rbtree sync-stress`.
+fn sync_stress(readers: usize, ops: usize, seed: u64) -> Result<(), String> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, RwLock};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SyncRBTree>();
+
+    let tree = Arc::new(RwLock::new(SyncRBTree::new()));
+    let done = Arc::new(AtomicBool::new(false));
+    let reader_threads: Vec<_> = (0..readers).map(|r| {
+        let tree = tree.clone();
+        let done = done.clone();
+        std::thread::spawn(move || -> Result<usize, String> {
+            let mut rng = Rng(seed ^ ((r as u64 + 1) << 32));
+            let mut rounds = 0;
+            while !done.load(Ordering::Acquire) {
+                let tree = tree.read().map_err(|_| "writer panicked".to_string())?;
+                tree.validate().map_err(|v| format!("reader {} saw a broken tree: {}", r, v))?;
+                let keys = tree.keys();
+                if keys.len() != tree.len() || keys.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(format!("reader {} saw {} sorted keys for len {}", r, keys.len(), tree.len()));
+                }
+                let probe = rng.between(0, 999);
+                if tree.contains(probe) != keys.binary_search(&probe).is_ok() {
+                    return Err(format!("reader {}: find {} disagrees with the key list", r, probe));
+                }
+                rounds += 1;
+            }
+            Ok(rounds)
+        })
+    }).collect();
+
+    let mut oracle = std::collections::BTreeSet::new();
+    let mut rng = Rng(seed);
+    let mut result = Ok(());
+    for step in 0..ops {
+        let key = rng.between(0, 999);
+        let mut tree = tree.write().unwrap();
+        let (changed, expected) = if rng.below(2) == 0 {
+            (tree.insert(key), oracle.insert(key))
+        } else {
+            (tree.delete(key), oracle.remove(&key))
+        };
+        if changed != expected || tree.len() != oracle.len() {
+            result = Err(format!("writer step {}: key {} disagrees with the oracle", step, key));
+            break;
+        }
+    }
+    done.store(true, Ordering::Release);
+    let mut rounds = 0;
+    for thread in reader_threads {
+        rounds += thread.join().map_err(|_| "reader panicked".to_string())??;
+    }
+    result?;
+
+    let tree = Arc::try_unwrap(tree).map_err(|_| "tree still shared".to_string())?.into_inner().unwrap();
+    let moved = std::thread::spawn(move || {
+        tree.validate().map(|_| (tree.keys(), tree.preorder_string()))
+    }).join().map_err(|_| "checker panicked".to_string())?;
+    let (keys, shape) = moved.map_err(|v| v.to_string())?;
+    if !keys.iter().eq(oracle.iter()) {
+        return Err("final contents differ from the oracle".to_string());
+    }
+    let parsed = parse_tree(&shape).map_err(|e| format!("final tree does not parse back: {}", e))?;
+    if preorder_string(parsed.as_ref()) != shape {
+        return Err("final tree changed shape on a round trip".to_string());
+    }
+    println!("ok: {} writes, {} reader rounds over {} threads, {} keys left", ops, rounds, readers, keys.len());
+    Ok(())
+}
+
+// Chỉ số rỗng trong các trường nguyên tử
+const NIL: usize = usize::MAX;
+// Chunk k of a `ConcurrentRBTree` holds CHUNK_BASE << k slots
+const CHUNK_BASE: usize = 64;
+const CHUNK_COUNT: usize = 40;
+// Slot 0 of a `ConcurrentRBTree` is a black sentinel whose right link is the root
+const HEAD: usize = 0;
+// Link indices, so that `(key > value) as usize` picks the side to go down
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+
+struct AtomicSlot {
+    // even while free to take; odd while a writer holds the slot
+    version: std::sync::atomic::AtomicU64,
+    value: std::sync::atomic::AtomicI32,
+    color: std::sync::atomic::AtomicU8,
+    links: [std::sync::atomic::AtomicUsize; 2],
+}
+
+impl AtomicSlot {
+    fn new() -> AtomicSlot {
+        use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, AtomicUsize};
+        AtomicSlot {
+            version: AtomicU64::new(0),
+            value: AtomicI32::new(0),
+            color: AtomicU8::new(Color::Black as u8),
+            links: [AtomicUsize::new(NIL), AtomicUsize::new(NIL)],
+        }
+    }
+}
+
+fn to_link(index: Option<usize>) -> usize {
+    index.unwrap_or(NIL)
+}
+
+fn from_link(link: usize) -> Option<usize> {
+    if link == NIL { None } else { Some(link) }
+}
+
+// Spin a little, then start yielding to whoever is in the way
+fn backoff(attempt: u32) {
+    if attempt > 8 {
+        std::thread::yield_now();
+    } else {
+        std::hint::spin_loop();
+    }
+}
+
+/// Red-black tree for many threads, with a lock word in every node instead
+/// of one lock for the whole tree. It uses the top-down insert and delete
+/// (Julienne Walker's formulation), which recolour and rotate on the way
+/// down and never walk back up, so a writer only needs the few nodes around
+/// its current position: it locks them hand over hand from a sentinel above
+/// the root and lets go of the ones it has left behind. Writers in different
+/// subtrees run in parallel; they only queue up near the root, which each of
+/// them passes through for a few steps. A delete that finds its key keeps
+/// everything from there down locked until it has moved the predecessor up.
+///
+/// Readers take no locks. They check each node's version before and after
+/// using it, and start over when a writer held or changed it, so `contains`
+/// is linearizable. `range` strings such lookups together and is not a
+/// snapshot. `validate` locks the whole tree, briefly blocking writers.
+///
+/// Slots live in chunks that never move or get freed, and a reused slot
+/// keeps counting versions, so a reader holding a stale index can read
+/// nonsense but never freed memory, and always notices.
+struct ConcurrentRBTree {
+    // fixed length, chunks are created on demand and never move
+    chunks: Vec<std::sync::OnceLock<Box<[AtomicSlot]>>>,
+    len: std::sync::atomic::AtomicUsize,
+    // only held to hand out or take back a slot
+    allocator: std::sync::Mutex<SlotAllocator>,
+}
+
+#[derive(Default)]
+struct SlotAllocator {
+    allocated: usize,
+    free: Vec<usize>,
+}
+
+// The slots one writer holds, and whether it changed the key or links of each
+struct SlotLocks<'a> {
+    tree: &'a ConcurrentRBTree,
+    held: Vec<(usize, bool)>,
+}
+
+impl<'a> SlotLocks<'a> {
+    fn new(tree: &'a ConcurrentRBTree) -> SlotLocks<'a> {
+        SlotLocks { tree, held: Vec::new() }
+    }
+
+    fn holds(&self, i: usize) -> bool {
+        self.held.iter().any(|&(h, _)| h == i)
+    }
+
+    fn lock(&mut self, i: usize) {
+        use std::sync::atomic::{fence, Ordering};
+        if self.holds(i) {
+            return;
+        }
+        let version = &self.tree.slot(i).version;
+        let mut attempt = 0;
+        loop {
+            let current = version.load(Ordering::Relaxed);
+            if current & 1 == 0
+                && version.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                break;
+            }
+            backoff(attempt);
+            attempt += 1;
+        }
+        // Readers that see any of our writes also see the odd version
+        fence(Ordering::Release);
+        self.held.push((i, false));
+    }
+
+    // Locks the child of held slot `i`; it cannot move away while we wait, since that takes `i`
+    fn lock_child(&mut self, i: usize, dir: usize) -> Option<usize> {
+        let child = self.child(i, dir)?;
+        self.lock(child);
+        Some(child)
+    }
+
+    // A changed slot gets a new version; an untouched one gets its old version back
+    fn unlock_at(&mut self, index: usize) {
+        use std::sync::atomic::Ordering;
+        let (i, changed) = self.held.swap_remove(index);
+        let version = &self.tree.slot(i).version;
+        let current = version.load(Ordering::Relaxed);
+        version.store(if changed { current + 1 } else { current - 1 }, Ordering::Release);
+    }
+
+    fn unlock_all_but(&mut self, keep: &[usize]) {
+        let mut index = 0;
+        while index < self.held.len() {
+            if keep.contains(&self.held[index].0) {
+                index += 1;
+            } else {
+                self.unlock_at(index);
+            }
+        }
+    }
+
+    fn change_all(&mut self) {
+        self.held.iter_mut().for_each(|entry| entry.1 = true);
+    }
+
+    fn changed(&mut self, i: usize) {
+        let entry = self.held.iter_mut().find(|(h, _)| *h == i).expect("changing a slot that is not held");
+        entry.1 = true;
+    }
+
+    fn value(&self, i: usize) -> i32 {
+        self.tree.slot(i).value.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn child(&self, i: usize, dir: usize) -> Option<usize> {
+        debug_assert!(self.holds(i), "reading links of a slot that is not held");
+        from_link(self.tree.slot(i).links[dir].load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    // The side of `parent` that `child` hangs on
+    fn side(&self, parent: usize, child: usize) -> usize {
+        if self.child(parent, RIGHT) == Some(child) { RIGHT } else { LEFT }
+    }
+
+    fn is_red(&self, i: Option<usize>) -> bool {
+        i.is_some_and(|i| {
+            debug_assert!(self.holds(i), "reading the colour of a slot that is not held");
+            self.tree.slot(i).color.load(std::sync::atomic::Ordering::Relaxed) == Color::Red as u8
+        })
+    }
+
+    fn set_value(&mut self, i: usize, value: i32) {
+        self.changed(i);
+        self.tree.slot(i).value.store(value, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn set_child(&mut self, i: usize, dir: usize, child: Option<usize>) {
+        self.changed(i);
+        self.tree.slot(i).links[dir].store(to_link(child), std::sync::atomic::Ordering::Relaxed);
+    }
+
+    // Readers never look at colours, so this does not count as a change
+    fn set_color(&mut self, i: usize, color: Color) {
+        debug_assert!(self.holds(i), "recolouring a slot that is not held");
+        self.tree.slot(i).color.store(color as u8, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    // Called after every step that held the sentinel, so other writers never see a red root
+    fn blacken_root(&mut self) {
+        if self.holds(HEAD) {
+            if let Some(root) = self.child(HEAD, RIGHT) {
+                self.set_color(root, Color::Black);
+            }
+        }
+    }
+
+    // Lifts the child of `root` opposite `dir` into its place and returns it
+    fn rotate(&mut self, root: usize, dir: usize) -> usize {
+        let lifted = self.child(root, 1 - dir).expect("rotating without a child to lift");
+        let inner = self.child(lifted, dir);
+        self.set_child(root, 1 - dir, inner);
+        self.set_child(lifted, dir, Some(root));
+        self.set_color(root, Color::Red);
+        self.set_color(lifted, Color::Black);
+        lifted
+    }
+
+    fn rotate_double(&mut self, root: usize, dir: usize) -> usize {
+        let child = self.child(root, 1 - dir).expect("double rotation without a child");
+        let lifted = self.rotate(child, 1 - dir);
+        self.set_child(root, 1 - dir, Some(lifted));
+        self.rotate(root, dir)
+    }
+
+    // A fresh red leaf holding `value`, held by this writer and not linked yet
+    fn alloc(&mut self, value: i32) -> usize {
+        use std::sync::atomic::Ordering;
+        let i = {
+            let mut allocator = self.tree.allocator.lock().unwrap();
+            match allocator.free.pop() {
+                Some(i) => i,
+                None => {
+                    let i = allocator.allocated;
+                    let (chunk, _) = chunk_of(i);
+                    self.tree.chunks[chunk].get_or_init(|| (0..CHUNK_BASE << chunk).map(|_| AtomicSlot::new()).collect());
+                    allocator.allocated += 1;
+                    i
+                },
+            }
+        };
+        self.lock(i);
+        let slot = self.tree.slot(i);
+        slot.value.store(value, Ordering::Relaxed);
+        slot.links[LEFT].store(NIL, Ordering::Relaxed);
+        slot.links[RIGHT].store(NIL, Ordering::Relaxed);
+        self.set_color(i, Color::Red);
+        self.changed(i);
+        i
+    }
+}
+
+impl<'a> Drop for SlotLocks<'a> {
+    fn drop(&mut self) {
+        while !self.held.is_empty() {
+            self.unlock_at(self.held.len() - 1);
+        }
+    }
+}
+
+// (chunk, offset) of slot `i`; chunk k starts at CHUNK_BASE * (2^k - 1)
+fn chunk_of(i: usize) -> (usize, usize) {
+    let n = i / CHUNK_BASE + 1;
+    let chunk = (usize::BITS - 1 - n.leading_zeros()) as usize;
+    (chunk, i - CHUNK_BASE * ((1 << chunk) - 1))
+}
+
+impl ConcurrentRBTree {
+    fn new() -> ConcurrentRBTree {
+        use std::sync::atomic::AtomicUsize;
+        let tree = ConcurrentRBTree {
+            chunks: (0..CHUNK_COUNT).map(|_| std::sync::OnceLock::new()).collect(),
+            len: AtomicUsize::new(0),
+            allocator: std::sync::Mutex::new(SlotAllocator { allocated: HEAD + 1, free: Vec::new() }),
+        };
+        tree.chunks[0].get_or_init(|| (0..CHUNK_BASE).map(|_| AtomicSlot::new()).collect());
+        tree
+    }
+
+    fn slot(&self, i: usize) -> &AtomicSlot {
+        self.try_slot(i).expect("link to a slot that was never allocated")
+    }
+
+    fn try_slot(&self, i: usize) -> Option<&AtomicSlot> {
+        let (chunk, offset) = chunk_of(i);
+        self.chunks.get(chunk)?.get().map(|slots| &slots[offset])
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    // Runs `read` until it gets through without meeting a writer
+    fn optimistic<T, F: FnMut() -> Option<T>>(&self, mut read: F) -> T {
+        let mut attempt = 0;
+        loop {
+            if let Some(result) = read() {
+                return result;
+            }
+            backoff(attempt);
+            attempt += 1;
+        }
+    }
+
+    // The version of slot `i`, or None while a writer holds it
+    fn read_version(&self, i: usize) -> Option<u64> {
+        let version = self.try_slot(i)?.version.load(std::sync::atomic::Ordering::Acquire);
+        if version & 1 == 0 { Some(version) } else { None }
+    }
+
+    // True when nothing was written to slot `i` since its `version` was read
+    fn unchanged(&self, i: usize, version: u64) -> bool {
+        use std::sync::atomic::{fence, Ordering};
+        fence(Ordering::Acquire);
+        self.slot(i).version.load(Ordering::Relaxed) == version
+    }
+
+    /// Walks down towards `key` without locking and shows each key passed to
+    /// `visit`, stopping early when it returns true. Every step reads the
+    /// child's version before checking that the parent did not change, so the
+    /// walk only follows links that were really there. Returns whether `visit`
+    /// stopped it, or None when a writer got in the way and the walk has to
+    /// start over.
+    fn walk<F: FnMut(i32) -> bool>(&self, key: i32, mut visit: F) -> Option<bool> {
+        use std::sync::atomic::Ordering;
+        let mut node = HEAD;
+        let mut version = self.read_version(HEAD)?;
+        let mut next = from_link(self.slot(HEAD).links[RIGHT].load(Ordering::Relaxed));
+        // A half-finished rotation seen out of order could make a cycle
+        for _ in 0..MAX_SLOT_DEPTH {
+            let child = match next {
+                None => return if self.unchanged(node, version) { Some(false) } else { None },
+                Some(child) => child,
+            };
+            let child_version = self.read_version(child)?;
+            if !self.unchanged(node, version) {
+                return None;
+            }
+            let slot = self.slot(child);
+            let value = slot.value.load(Ordering::Relaxed);
+            if visit(value) {
+                return if self.unchanged(child, child_version) { Some(true) } else { None };
+            }
+            next = from_link(slot.links[(key > value) as usize].load(Ordering::Relaxed));
+            node = child;
+            version = child_version;
+        }
+        None
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        self.optimistic(|| self.walk(key, |value| value == key))
+    }
+
+    // The smallest key >= `low`
+    fn ceiling(&self, low: i32) -> Option<i32> {
+        self.optimistic(|| {
+            let mut best = None;
+            self.walk(low, |value| {
+                if value >= low && best.is_none_or(|b| value < b) {
+                    best = Some(value);
+                }
+                value == low
+            })?;
+            Some(best)
+        })
+    }
+
+    /// Keys in `low..=high` in order, found one `ceiling` at a time. Each key
+    /// was there when it was looked up, but writes that land behind the scan
+    /// are missed, so this is not a snapshot.
+    fn range(&self, low: i32, high: i32) -> Vec<i32> {
+        let mut keys = Vec::new();
+        let mut next = Some(low);
+        while let Some(key) = next.and_then(|low| self.ceiling(low)).filter(|&k| k <= high) {
+            keys.push(key);
+            next = key.checked_add(1);
+        }
+        keys
+    }
+
+    fn keys(&self) -> Vec<i32> {
+        self.range(i32::MIN, i32::MAX)
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert(&self, x: i32) -> bool {
+        let mut locks = SlotLocks::new(self);
+        locks.lock(HEAD);
+        // The held part of the way down; its last four entries are t, g, p and q
+        let mut path = vec![HEAD];
+        let mut dir = RIGHT;
+        let mut added = false;
+        loop {
+            let p = *path.last().unwrap();
+            let q = match locks.lock_child(p, dir) {
+                Some(q) => q,
+                None => {
+                    let q = locks.alloc(x);
+                    locks.set_child(p, dir, Some(q));
+                    added = true;
+                    q
+                },
+            };
+            path.push(q);
+            let (left, right) = (locks.lock_child(q, LEFT), locks.lock_child(q, RIGHT));
+            // Colour flip: a black node with two red children
+            if locks.is_red(left) && locks.is_red(right) {
+                locks.set_color(q, Color::Red);
+                locks.set_color(left.unwrap(), Color::Black);
+                locks.set_color(right.unwrap(), Color::Black);
+            }
+            // Red node under a red parent; the root is black, so the parent has a parent too
+            let n = path.len();
+            if locks.is_red(Some(q)) && locks.is_red(Some(p)) {
+                let (t, g) = (path[n - 4], path[n - 3]);
+                let last = locks.side(g, p);
+                let top = if locks.child(p, last) == Some(q) {
+                    locks.rotate(g, 1 - last)
+                } else {
+                    locks.rotate_double(g, 1 - last)
+                };
+                let side = locks.side(t, g);
+                locks.set_child(t, side, Some(top));
+                if top == p {
+                    path.remove(n - 3);
+                } else {
+                    path.drain(n - 3..n - 1);
+                }
+            }
+            locks.blacken_root();
+            let value = locks.value(q);
+            if value == x {
+                break;
+            }
+            dir = (x > value) as usize;
+            let mut keep = path[path.len().saturating_sub(3)..].to_vec();
+            keep.extend(locks.child(q, dir));
+            locks.unlock_all_but(&keep);
+        }
+        if added {
+            self.len.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        }
+        added
+    }
+
+    // Returns true when `key` was there
+    fn delete(&self, key: i32) -> bool {
+        let mut locks = SlotLocks::new(self);
+        locks.lock(HEAD);
+        // The held part of the way down; its last three entries are g, p and q
+        let mut path = vec![HEAD];
+        let mut dir = RIGHT;
+        let mut found = None;
+        while let Some(q) = locks.lock_child(*path.last().unwrap(), dir) {
+            let (p, last) = (*path.last().unwrap(), dir);
+            path.push(q);
+            let value = locks.value(q);
+            // On a match keep going left, down to the predecessor
+            dir = (key > value) as usize;
+            if value == key {
+                found = Some(q);
+            }
+            let (near, far) = (locks.lock_child(q, dir), locks.lock_child(q, 1 - dir));
+            // Push a red node down, so the node finally unlinked is red
+            if !locks.is_red(Some(q)) && !locks.is_red(near) {
+                if locks.is_red(far) {
+                    let top = locks.rotate(q, dir);
+                    locks.set_child(p, last, Some(top));
+                    path.insert(path.len() - 1, top);
+                } else if let Some(s) = locks.lock_child(p, 1 - last) {
+                    let (s_near, s_far) = (locks.lock_child(s, last), locks.lock_child(s, 1 - last));
+                    if !locks.is_red(s_near) && !locks.is_red(s_far) {
+                        locks.set_color(p, Color::Black);
+                        locks.set_color(s, Color::Red);
+                        locks.set_color(q, Color::Red);
+                    } else {
+                        let g = path[path.len() - 3];
+                        let side = locks.side(g, p);
+                        let top = if locks.is_red(s_near) { locks.rotate_double(p, last) } else { locks.rotate(p, last) };
+                        locks.set_child(g, side, Some(top));
+                        locks.set_color(q, Color::Red);
+                        locks.set_color(top, Color::Red);
+                        for child in [locks.child(top, LEFT), locks.child(top, RIGHT)].iter().flatten() {
+                            locks.set_color(*child, Color::Black);
+                        }
+                        path.insert(path.len() - 2, top);
+                    }
+                }
+            }
+            locks.blacken_root();
+            // Past the match every node on the way down stays held; see `contains`
+            if found.is_none() {
+                let mut keep = path[path.len() - 2..].to_vec();
+                keep.extend(near);
+                locks.unlock_all_but(&keep);
+            }
+        }
+        let f = match found {
+            Some(f) => f,
+            None => return false,
+        };
+        let q = path[path.len() - 1];
+        let p = path[path.len() - 2];
+        let value = locks.value(q);
+        locks.set_value(f, value);
+        let child = locks.child(q, LEFT).or(locks.child(q, RIGHT));
+        let side = locks.side(p, q);
+        locks.set_child(p, side, child);
+        // Every node passed since the match lost `value` from its subtree, so
+        // readers that went down there before the copy must start over
+        locks.change_all();
+        locks.blacken_root();
+        self.len.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+        drop(locks);
+        // Nobody can be waiting for `q`: that would take holding its old parent
+        self.allocator.lock().unwrap().free.push(q);
+        true
+    }
+
+    /// Full check of every rule except parent links, which this tree does not
+    /// keep. Locks the whole tree top-down first, so it waits for writers
+    /// already inside and holds up new ones until it is done.
+    fn validate(&self) -> Result<i32, Violation> {
+        let mut locks = SlotLocks::new(self);
+        locks.lock(HEAD);
+        let mut stack = vec![HEAD];
+        while let Some(i) = stack.pop() {
+            stack.extend(locks.lock_child(i, LEFT));
+            stack.extend(locks.lock_child(i, RIGHT));
+        }
+        validate_linked(locks.child(HEAD, RIGHT), &|&i: &usize| {
+            let color = if locks.is_red(Some(i)) { Color::Red } else { Color::Black };
+            (locks.value(i), color, locks.child(i, LEFT), locks.child(i, RIGHT))
+        })
+    }
+}
+
+// One shard's keys in a `SyncRBTree`, with their values beside them
+#[derive(Debug, Clone, Default)]
+struct ShardTree<V> {
+    keys: SyncRBTree,
+    values: std::collections::HashMap<i32, V>,
+}
+
+impl<V: Clone> ShardTree<V> {
+    fn from_pairs(pairs: &[(i32, V)]) -> ShardTree<V> {
+        let mut tree = ShardTree { keys: SyncRBTree::new(), values: std::collections::HashMap::new() };
+        for (k, v) in pairs {
+            tree.insert(*k, v.clone());
+        }
+        tree
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    // Returns the value `k` had before, if any
+    fn insert(&mut self, k: i32, v: V) -> Option<V> {
+        self.keys.insert(k);
+        self.values.insert(k, v)
+    }
+
+    // Returns the value `key` had, if it was there
+    fn delete(&mut self, key: i32) -> Option<V> {
+        let old = self.values.remove(&key);
+        if old.is_some() {
+            self.keys.delete(key);
+        }
+        old
+    }
+
+    fn range(&self, low: i32, high: i32) -> Vec<(i32, V)> {
+        self.keys.range(low, high).into_iter().map(|k| (k, self.values[&k].clone())).collect()
+    }
+}
+
+struct Shard<V> {
+    // smallest key routed here; the next shard's `low` is the exclusive upper bound
+    low: i32,
+    tree: std::sync::RwLock<ShardTree<V>>,
+}
+
+/// Ordered map from `i32` keys to `V`, split into range shards, each a
+/// `SyncRBTree` of keys plus their values behind its own lock, so threads
+/// working on different key ranges do not contend. A shard that grows past
+/// `max_shard_len` is split at its median; two neighbours that together
+/// fall under a quarter of that are joined. Point operations hold the shard
+/// directory's read lock, resharding takes its write lock.
+struct ShardedRBTreeMap<V> {
+    shards: std::sync::RwLock<Vec<Shard<V>>>,
+    max_shard_len: usize,
+}
+
+impl<V: Clone> ShardedRBTreeMap<V> {
+    /// `shards` equal-width shards over `low..=high` to start with, but no
+    /// more than there are keys in it, so no two shards start at the same
+    /// key. Keys outside that range go to the first or last shard.
+    fn new(shards: usize, low: i32, high: i32, max_shard_len: usize) -> ShardedRBTreeMap<V> {
+        let span = (high as i64 - low as i64 + 1).max(1);
+        let shards = (shards as i64).clamp(1, span) as usize;
+        let width = span / shards as i64;
+        let directory = (0..shards)
+            .map(|i| Shard {
+                low: if i == 0 { i32::MIN } else { (low as i64 + width * i as i64).min(i32::MAX as i64) as i32 },
+                tree: std::sync::RwLock::new(ShardTree::from_pairs(&[])),
+            })
+            .collect();
+        ShardedRBTreeMap { shards: std::sync::RwLock::new(directory), max_shard_len: max_shard_len.max(2) }
+    }
+
+    fn shard_index(shards: &[Shard<V>], key: i32) -> usize {
+        shards.partition_point(|s| s.low <= key) - 1
+    }
+
+    fn get(&self, key: i32) -> Option<V> {
+        let shards = self.shards.read().unwrap();
+        let tree = shards[Self::shard_index(&shards, key)].tree.read().unwrap();
+        tree.values.get(&key).cloned()
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        let shards = self.shards.read().unwrap();
+        let tree = shards[Self::shard_index(&shards, key)].tree.read().unwrap();
+        tree.values.contains_key(&key)
+    }
+
+    // Returns the value `k` had before, if any
+    fn insert(&self, k: i32, v: V) -> Option<V> {
+        let (old, index, len) = {
+            let shards = self.shards.read().unwrap();
+            let index = Self::shard_index(&shards, k);
+            let mut tree = shards[index].tree.write().unwrap();
+            (tree.insert(k, v), index, tree.len())
+        };
+        if len > self.max_shard_len {
+            self.split(index, k);
+        }
+        old
+    }
+
+    // Returns the value `key` had, if it was there
+    fn delete(&self, key: i32) -> Option<V> {
+        let (old, index) = {
+            let shards = self.shards.read().unwrap();
+            let index = Self::shard_index(&shards, key);
+            let old = shards[index].tree.write().unwrap().delete(key);
+            (old, index)
+        };
+        if old.is_some() {
+            self.join(index, key);
+        }
+        old
+    }
+
+    fn len(&self) -> usize {
+        let shards = self.shards.read().unwrap();
+        shards.iter().map(|s| s.tree.read().unwrap().len()).sum()
+    }
+
+    fn shard_count(&self) -> usize {
+        self.shards.read().unwrap().len()
+    }
+
+    // (low bound, key count) of every shard
+    fn shard_sizes(&self) -> Vec<(i32, usize)> {
+        let shards = self.shards.read().unwrap();
+        shards.iter().map(|s| (s.low, s.tree.read().unwrap().len())).collect()
+    }
+
+    /// Entries with keys in `low..=high` in key order, read shard by shard.
+    /// Each shard is read consistently, but the whole range is not a
+    /// snapshot: writes to shards not yet reached are seen, and resharding
+    /// in between is harmless.
+    fn range(&self, low: i32, high: i32) -> ShardedRange<'_, V> {
+        ShardedRange { map: self, next_low: if low <= high { Some(low) } else { None }, high, buffer: Vec::new().into_iter() }
+    }
+
+    fn iter(&self) -> ShardedRange<'_, V> {
+        self.range(i32::MIN, i32::MAX)
+    }
+
+    // Splits shard `index` at its median if it is still too big and still holds `key`
+    fn split(&self, index: usize, key: i32) {
+        let mut shards = self.shards.write().unwrap();
+        if index >= shards.len() || Self::shard_index(&shards, key) != index {
+            return;
+        }
+        let pairs = shards[index].tree.read().unwrap().range(i32::MIN, i32::MAX);
+        if pairs.len() <= self.max_shard_len {
+            return;
+        }
+        let middle = pairs.len() / 2;
+        let (lower, upper) = pairs.split_at(middle);
+        *shards[index].tree.write().unwrap() = ShardTree::from_pairs(lower);
+        shards.insert(index + 1, Shard { low: upper[0].0, tree: std::sync::RwLock::new(ShardTree::from_pairs(upper)) });
+    }
+
+    // Joins shard `index` with a neighbour when both together are small
+    fn join(&self, index: usize, key: i32) {
+        let small = self.max_shard_len / 4;
+        {
+            let shards = self.shards.read().unwrap();
+            if shards.len() < 2 || index >= shards.len() || shards[index].tree.read().unwrap().len() > small {
+                return;
+            }
+        }
+        let mut shards = self.shards.write().unwrap();
+        if shards.len() < 2 || index >= shards.len() || Self::shard_index(&shards, key) != index {
+            return;
+        }
+        let neighbour = if index + 1 < shards.len() { index + 1 } else { index - 1 };
+        let (left, right) = (index.min(neighbour), index.max(neighbour));
+        let left_len = shards[left].tree.read().unwrap().len();
+        let right_len = shards[right].tree.read().unwrap().len();
+        if left_len + right_len > small {
+            return;
+        }
+        let moved = shards.remove(right).tree.into_inner().unwrap();
+        let mut tree = shards[left].tree.write().unwrap();
+        for (k, v) in moved.range(i32::MIN, i32::MAX) {
+            tree.insert(k, v);
+        }
+    }
+
+    /// Validates every shard, checks that its keys respect the boundaries
+    /// and that every key has exactly one value.
+    fn validate(&self) -> Result<(), String> {
+        let shards = self.shards.read().unwrap();
+        for (i, shard) in shards.iter().enumerate() {
+            let tree = shard.tree.read().unwrap();
+            tree.keys.validate().map_err(|v| format!("shard {} (from {}): {}", i, shard.low, v))?;
+            let upper = shards.get(i + 1).map(|s| s.low);
+            let keys = tree.keys.keys();
+            if keys.first().is_some_and(|&k| k < shard.low) || keys.last().is_some_and(|&k| upper.is_some_and(|u| k >= u)) {
+                return Err(format!("shard {} holds keys outside [{}, {:?})", i, shard.low, upper));
+            }
+            if tree.values.len() != keys.len() || keys.iter().any(|k| !tree.values.contains_key(k)) {
+                return Err(format!("shard {} has {} keys but {} values", i, keys.len(), tree.values.len()));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ShardedRange<'a, V> {
+    map: &'a ShardedRBTreeMap<V>,
+    // first key of the next shard to read, None when done
+    next_low: Option<i32>,
+    high: i32,
+    buffer: std::vec::IntoIter<(i32, V)>,
+}
+
+impl<'a, V: Clone> Iterator for ShardedRange<'a, V> {
+    type Item = (i32, V);
+
+    fn next(&mut self) -> Option<(i32, V)> {
+        loop {
+            if let Some(entry) = self.buffer.next() {
+                return Some(entry);
+            }
+            let low = self.next_low?;
+            let shards = self.map.shards.read().unwrap();
+            let index = ShardedRBTreeMap::shard_index(&shards, low);
+            let upper = shards.get(index + 1).map(|s| s.low);
+            let high = upper.map_or(self.high, |u| self.high.min(u - 1));
+            self.buffer = shards[index].tree.read().unwrap().range(low, high).into_iter();
+            self.next_low = upper.filter(|&u| u <= self.high);
+        }
+    }
+}
+
+/// `rbtree sharded`: `threads` threads each own the keys congruent to their
+/// number and run `ops` random inserts, deletes and lookups on one shared
+/// map, with range reads in between. Afterwards the shards are validated
+/// and the contents compared with the union of the per-thread oracles.
+fn sharded_stress(threads: usize, ops: usize, seed: u64) -> Result<(), String> {
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ShardedRBTreeMap<u64>>();
+
+    let threads = threads.max(1);
+    let map = Arc::new(ShardedRBTreeMap::new(4, 0, 99_999, 2_000));
+    let workers: Vec<_> = (0..threads).map(|thread| {
+        let map = map.clone();
+        std::thread::spawn(move || -> Result<std::collections::BTreeMap<i32, u64>, String> {
+            let mut rng = Rng(seed ^ ((thread as u64 + 1) << 32));
+            let mut oracle = std::collections::BTreeMap::new();
+            for step in 0..ops {
+                let key = rng.between(0, 99_999 / threads as i32) * threads as i32 + thread as i32;
+                let value = rng.next();
+                // Chèn nhiều hơn xóa để các mảnh lớn dần và phải tách
+                let (got, expected) = match rng.below(4) {
+                    0 | 1 => (map.insert(key, value), oracle.insert(key, value)),
+                    2 => (map.delete(key), oracle.remove(&key)),
+                    _ => (map.get(key), oracle.get(&key).cloned()),
+                };
+                if got != expected || map.contains(key) != oracle.contains_key(&key) {
+                    return Err(format!("thread {} step {}: key {} disagrees with the oracle", thread, step, key));
+                }
+                if step % 256 == 0 {
+                    let low = rng.between(0, 99_999);
+                    let keys: Vec<i32> = map.range(low, low + 5_000).map(|(k, _)| k).collect();
+                    if keys.windows(2).any(|w| w[0] >= w[1]) {
+                        return Err(format!("thread {}: range from {} is out of order", thread, low));
+                    }
+                }
+            }
+            Ok(oracle)
+        })
+    }).collect();
+
+    let mut expected = std::collections::BTreeMap::new();
+    for worker in workers {
+        expected.extend(worker.join().map_err(|_| "worker panicked".to_string())??);
+    }
+    map.validate()?;
+    if !map.iter().eq(expected.iter().map(|(&k, &v)| (k, v))) || map.len() != expected.len() {
+        return Err("final contents differ from the oracles".to_string());
+    }
+    // Xóa gần hết để các mảnh nhỏ được gộp lại
+    let remaining: Vec<(i32, u64)> = expected.iter().map(|(&k, &v)| (k, v)).collect();
+    let peak = map.shard_sizes();
+    for (key, _) in remaining.iter().skip(100) {
+        map.delete(*key);
+    }
+    map.validate()?;
+    if !map.iter().eq(remaining.iter().take(100).cloned()) {
+        return Err("contents after joining differ".to_string());
+    }
+    println!("ok: {} keys over {} shards (largest {}) at the end of the run, {} shards after deleting down to {}",
+        expected.len(), peak.len(), peak.iter().map(|&(_, len)| len).max().unwrap_or(0), map.shard_count(), map.len());
+    Ok(())
+}
+
+type PLink = Option<std::sync::Arc<PNode>>;
+
+// Nút bất biến: thay đổi thì chép lại đường đi, phần còn lại dùng chung
+#[derive(Debug)]
+struct PNode {
+    color: Color,
+    left: PLink,
+    value: i32,
+    right: PLink,
+}
+
+fn pnode(color: Color, left: PLink, value: i32, right: PLink) -> PLink {
+    Some(std::sync::Arc::new(PNode { color, left, value, right }))
+}
+
+fn p_is_red(link: &PLink) -> bool {
+    link.as_ref().is_some_and(|n| n.color == Color::Red)
+}
+
+fn p_is_black_node(link: &PLink) -> bool {
+    link.as_ref().is_some_and(|n| n.color == Color::Black)
+}
+
+fn p_recolor(link: &PLink, color: Color) -> PLink {
+    let n = link.as_ref().expect("recolouring an empty subtree");
+    pnode(color, n.left.clone(), n.value, n.right.clone())
+}
+
+// Okasaki's four red-red shapes plus Kahrs' case of two red children
+fn p_balance(left: PLink, value: i32, right: PLink) -> PLink {
+    use Color::{Black, Red};
+    if p_is_red(&left) && p_is_red(&right) {
+        return pnode(Red, p_recolor(&left, Black), value, p_recolor(&right, Black));
+    }
+    if let Some(l) = left.as_ref().filter(|l| l.color == Red) {
+        if let Some(ll) = l.left.as_ref().filter(|n| n.color == Red) {
+            return pnode(Red, pnode(Black, ll.left.clone(), ll.value, ll.right.clone()), l.value,
+                pnode(Black, l.right.clone(), value, right));
+        }
+        if let Some(lr) = l.right.as_ref().filter(|n| n.color == Red) {
+            return pnode(Red, pnode(Black, l.left.clone(), l.value, lr.left.clone()), lr.value,
+                pnode(Black, lr.right.clone(), value, right));
+        }
+    }
+    if let Some(r) = right.as_ref().filter(|r| r.color == Red) {
+        if let Some(rr) = r.right.as_ref().filter(|n| n.color == Red) {
+            return pnode(Red, pnode(Black, left, value, r.left.clone()), r.value,
+                pnode(Black, rr.left.clone(), rr.value, rr.right.clone()));
+        }
+        if let Some(rl) = r.left.as_ref().filter(|n| n.color == Red) {
+            return pnode(Red, pnode(Black, left, value, rl.left.clone()), rl.value,
+                pnode(Black, rl.right.clone(), r.value, r.right.clone()));
+        }
+    }
+    pnode(Black, left, value, right)
+}
+
+fn p_blacken(link: PLink) -> PLink {
+    if p_is_red(&link) { p_recolor(&link, Color::Black) } else { link }
+}
+
+fn p_insert(link: &PLink, x: i32) -> PLink {
+    let n = match link {
+        None => return pnode(Color::Red, None, x, None),
+        Some(n) => n,
+    };
+    if x == n.value {
+        return link.clone();
+    }
+    let (left, right) = if x < n.value {
+        (p_insert(&n.left, x), n.right.clone())
+    } else {
+        (n.left.clone(), p_insert(&n.right, x))
+    };
+    if n.color == Color::Black { p_balance(left, n.value, right) } else { pnode(Color::Red, left, n.value, right) }
+}
+
+// Kahrs' deletion; `x` must be in the tree
+fn p_delete(link: &PLink, x: i32) -> PLink {
+    let n = match link {
+        None => return None,
+        Some(n) => n,
+    };
+    if x < n.value {
+        if p_is_black_node(&n.left) {
+            p_balance_left(p_delete(&n.left, x), n.value, n.right.clone())
+        } else {
+            pnode(Color::Red, p_delete(&n.left, x), n.value, n.right.clone())
+        }
+    } else if x > n.value {
+        if p_is_black_node(&n.right) {
+            p_balance_right(n.left.clone(), n.value, p_delete(&n.right, x))
+        } else {
+            pnode(Color::Red, n.left.clone(), n.value, p_delete(&n.right, x))
+        }
+    } else {
+        p_fuse(&n.left, &n.right)
+    }
+}
+
+// The left side lost one black level
+fn p_balance_left(left: PLink, value: i32, right: PLink) -> PLink {
+    use Color::{Black, Red};
+    if p_is_red(&left) {
+        return pnode(Red, p_recolor(&left, Black), value, right);
+    }
+    let r = right.as_ref().expect("left-heavy rebalance on an invalid tree");
+    if r.color == Black {
+        return p_balance(left, value, p_recolor(&right, Red));
+    }
+    let rl = r.left.as_ref().expect("red node without black children");
+    pnode(Red, pnode(Black, left, value, rl.left.clone()), rl.value,
+        p_balance(rl.right.clone(), r.value, p_recolor(&r.right, Red)))
+}
+
+// The right side lost one black level
+fn p_balance_right(left: PLink, value: i32, right: PLink) -> PLink {
+    use Color::{Black, Red};
+    if p_is_red(&right) {
+        return pnode(Red, left, value, p_recolor(&right, Black));
+    }
+    let l = left.as_ref().expect("right-heavy rebalance on an invalid tree");
+    if l.color == Black {
+        return p_balance(p_recolor(&left, Red), value, right);
+    }
+    let lr = l.right.as_ref().expect("red node without black children");
+    pnode(Red, p_balance(p_recolor(&l.left, Red), l.value, lr.left.clone()), lr.value,
+        pnode(Black, lr.right.clone(), value, right))
+}
+
+// Joins the two subtrees of a deleted node
+fn p_fuse(left: &PLink, right: &PLink) -> PLink {
+    use Color::{Black, Red};
+    let (l, r) = match (left, right) {
+        (None, _) => return right.clone(),
+        (_, None) => return left.clone(),
+        (Some(l), Some(r)) => (l, r),
+    };
+    match (l.color, r.color) {
+        (Black, Red) => pnode(Red, p_fuse(left, &r.left), r.value, r.right.clone()),
+        (Red, Black) => pnode(Red, l.left.clone(), l.value, p_fuse(&l.right, right)),
+        (Red, Red) => {
+            let middle = p_fuse(&l.right, &r.left);
+            if p_is_red(&middle) {
+                let m = middle.as_ref().unwrap();
+                pnode(Red, pnode(Red, l.left.clone(), l.value, m.left.clone()), m.value,
+                    pnode(Red, m.right.clone(), r.value, r.right.clone()))
+            } else {
+                pnode(Red, l.left.clone(), l.value, pnode(Red, middle, r.value, r.right.clone()))
+            }
+        },
+        (Black, Black) => {
+            let middle = p_fuse(&l.right, &r.left);
+            if p_is_red(&middle) {
+                let m = middle.as_ref().unwrap();
+                pnode(Red, pnode(Black, l.left.clone(), l.value, m.left.clone()), m.value,
+                    pnode(Black, m.right.clone(), r.value, r.right.clone()))
+            } else {
+                p_balance_left(l.left.clone(), l.value, pnode(Black, middle, r.value, r.right.clone()))
+            }
+        },
+    }
+}
+
+fn p_contains(link: &PLink, key: i32) -> bool {
+    let mut current = link;
+    while let Some(n) = current {
+        if key == n.value {
+            return true;
+        }
+        current = if key < n.value { &n.left } else { &n.right };
+    }
+    false
+}
+
+fn p_count(link: &PLink) -> usize {
+    link.as_ref().map_or(0, |n| 1 + p_count(&n.left) + p_count(&n.right))
+}
+
+fn write_p_preorder(link: &PLink, parent: Option<i32>, out: &mut String) {
+    if let Some(n) = link {
+        out.push_str(&format!("{}-{}-p:{} ", n.value, n.color as i32, parent.map_or("N".to_string(), |p| p.to_string())));
+        write_p_preorder(&n.left, Some(n.value), out);
+        write_p_preorder(&n.right, Some(n.value), out);
+    }
+}
+
+/// A frozen version of a `CowRBTree`. It owns its root, so it can be kept,
+/// cloned and sent to other threads; reading it never takes a lock, and
+/// nodes it shares with newer versions are never changed.
+#[derive(Debug, Clone, Default)]
+struct Snapshot {
+    root: PLink,
+    len: usize,
+    // number of writes published before this snapshot
+    version: u64,
+}
+
+impl Snapshot {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        p_contains(&self.root, key)
+    }
+
+    fn iter(&self) -> SnapshotIter {
+        self.range(i32::MIN, i32::MAX)
+    }
+
+    fn range(&self, low: i32, high: i32) -> SnapshotIter {
+        let mut iter = SnapshotIter { stack: Vec::new(), low, high };
+        iter.descend(self.root.clone());
+        iter
+    }
+
+    fn keys(&self) -> Vec<i32> {
+        self.iter().collect()
+    }
+
+    fn preorder_string(&self) -> String {
+        let mut out = String::new();
+        write_p_preorder(&self.root, None, &mut out);
+        out
+    }
+
+    // Full check of every rule except parent links, which `PNode` does not have; O(n)
+    fn validate(&self) -> Result<i32, Violation> {
+        validate_linked(self.root.clone(), &|n: &std::sync::Arc<PNode>| (n.value, n.color, n.left.clone(), n.right.clone()))
+    }
+
+    // The next version with `x` added, or None when it is already there
+    fn inserted(&self, x: i32) -> Option<Snapshot> {
+        if self.contains(x) {
+            return None;
+        }
+        Some(Snapshot { root: p_blacken(p_insert(&self.root, x)), len: self.len + 1, version: self.version + 1 })
+    }
+
+    // The next version with `key` removed, or None when it is not there
+    fn deleted(&self, key: i32) -> Option<Snapshot> {
+        if !self.contains(key) {
+            return None;
+        }
+        Some(Snapshot { root: p_blacken(p_delete(&self.root, key)), len: self.len - 1, version: self.version + 1 })
+    }
+
+    // Nodes of `self` that are the very same allocation as a node in `other`
+    fn shared_nodes(&self, other: &Snapshot) -> usize {
+        fn collect(link: &PLink, out: &mut std::collections::HashSet<*const PNode>) {
+            if let Some(n) = link {
+                out.insert(std::sync::Arc::as_ptr(n));
+                collect(&n.left, out);
+                collect(&n.right, out);
+            }
+        }
+        let (mut mine, mut theirs) = (std::collections::HashSet::new(), std::collections::HashSet::new());
+        collect(&self.root, &mut mine);
+        collect(&other.root, &mut theirs);
+        mine.intersection(&theirs).count()
+    }
+}
+
+/// In-order walk over a snapshot; it holds its own references to the
+/// nodes, so it keeps working after the tree moves on.
+struct SnapshotIter {
+    stack: Vec<std::sync::Arc<PNode>>,
+    low: i32,
+    high: i32,
+}
+
+impl SnapshotIter {
+    fn descend(&mut self, mut link: PLink) {
+        while let Some(n) = link {
+            link = if self.low < n.value { n.left.clone() } else { None };
+            if n.value >= self.low {
+                self.stack.push(n);
+            } else {
+                link = n.right.clone();
+            }
+        }
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<i32> {
+        let n = self.stack.pop()?;
+        if n.value > self.high {
+            self.stack.clear();
+            return None;
+        }
+        self.descend(n.right.clone());
+        Some(n.value)
+    }
+}
+
+/// Copy-on-write tree: every write builds a new root by copying the path it
+/// changes and publishes it, so `snapshot()` is an O(1) handle sharing all
+/// untouched nodes with the live tree. Writers are serialized by their own
+/// lock and do all their work before publishing the new version with one
+/// atomic pointer swap. Readers take no lock and never wait: they announce
+/// themselves in `readers`, load the pointer and take their own reference.
+/// A replaced version is released only once no reader is between those
+/// steps, so it is never freed under one that has not counted it yet.
+struct CowRBTree {
+    // from `Arc::into_raw`; owns one strong count of the latest version
+    current: std::sync::atomic::AtomicPtr<Snapshot>,
+    // readers that may have loaded `current` but not counted their reference yet
+    readers: std::sync::atomic::AtomicUsize,
+    // the writer lock; guards replaced versions such a reader may still count
+    retired: std::sync::Mutex<Vec<std::sync::Arc<Snapshot>>>,
+}
+
+impl CowRBTree {
+    fn new() -> CowRBTree {
+        use std::sync::atomic::{AtomicPtr, AtomicUsize};
+        let empty = std::sync::Arc::into_raw(std::sync::Arc::new(Snapshot::default()));
+        CowRBTree {
+            current: AtomicPtr::new(empty as *mut Snapshot),
+            readers: AtomicUsize::new(0),
+            retired: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn latest(&self) -> std::sync::Arc<Snapshot> {
+        use std::sync::atomic::Ordering;
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let latest = self.current.load(Ordering::SeqCst);
+        // SAFETY: `latest` came from `Arc::into_raw`. If a writer has swapped
+        // it out since, it sits in `retired`, which is only cleared while
+        // `readers` is zero, and we are counted there until after this block.
+        let latest = unsafe {
+            std::sync::Arc::increment_strong_count(latest);
+            std::sync::Arc::from_raw(latest)
+        };
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        latest
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        (*self.latest()).clone()
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        self.latest().contains(key)
+    }
+
+    fn len(&self) -> usize {
+        self.latest().len
+    }
+
+    // Builds the next version from the latest one and publishes it if it changed
+    fn write<F: FnOnce(&Snapshot) -> Option<Snapshot>>(&self, change: F) -> bool {
+        use std::sync::atomic::Ordering;
+        let mut retired = self.retired.lock().unwrap();
+        let next = match change(&self.latest()) {
+            None => return false,
+            Some(next) => std::sync::Arc::into_raw(std::sync::Arc::new(next)),
+        };
+        let old = self.current.swap(next as *mut Snapshot, Ordering::SeqCst);
+        // SAFETY: `old` came from `Arc::into_raw`, and the count `current` held is now ours
+        retired.push(unsafe { std::sync::Arc::from_raw(old) });
+        // A reader that loaded `old` was counted in `readers` before the swap
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            retired.clear();
+        }
+        true
+    }
+
+    // Returns true when `x` was not there yet
+    fn insert(&self, x: i32) -> bool {
+        self.write(|latest| latest.inserted(x))
+    }
+
+    // Returns true when `key` was there
+    fn delete(&self, key: i32) -> bool {
+        self.write(|latest| latest.deleted(key))
+    }
+}
+
+impl Drop for CowRBTree {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` means no reader is left, and `current` still owns its count
+        drop(unsafe { std::sync::Arc::from_raw(*self.current.get_mut()) });
+    }
+}
+
+/// `rbtree cow`: one writer mutates a `CowRBTree` while reader threads take
+/// snapshots, validate them, and check that a snapshot still reads the same
+/// after the writer has moved on. The final version must match the oracle
+/// and read back through `parse_tree`. Ends with a structure-sharing report.
+fn cow_stress(readers: usize, ops: usize, seed: u64) -> Result<(), String> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CowRBTree>();
+    assert_send_sync::<Snapshot>();
+
+    let tree = Arc::new(CowRBTree::new());
+    let done = Arc::new(AtomicBool::new(false));
+    let reader_threads: Vec<_> = (0..readers).map(|r| {
+        let tree = tree.clone();
+        let done = done.clone();
+        std::thread::spawn(move || -> Result<usize, String> {
+            let mut rounds = 0;
+            let mut last_version = 0;
+            while !done.load(Ordering::Acquire) {
+                let snapshot = tree.snapshot();
+                if snapshot.version() < last_version {
+                    return Err(format!("reader {} went back from version {} to {}", r, last_version, snapshot.version()));
+                }
+                last_version = snapshot.version();
+                let first = snapshot.keys();
+                snapshot.validate().map_err(|v| format!("reader {}: version {} is broken: {}", r, last_version, v))?;
+                if first.len() != snapshot.len() || first.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(format!("reader {}: version {} lists {} keys for len {}", r, last_version, first.len(), snapshot.len()));
+                }
+                std::thread::yield_now();
+                if !snapshot.iter().eq(first.iter().cloned()) {
+                    return Err(format!("reader {}: version {} changed under the reader", r, last_version));
+                }
+                rounds += 1;
+            }
+            Ok(rounds)
+        })
+    }).collect();
+
+    let mut oracle = std::collections::BTreeSet::new();
+    let mut rng = Rng(seed);
+    let mut result = Ok(());
+    for step in 0..ops {
+        let key = rng.between(0, 4_999);
+        let (changed, expected) = if rng.below(2) == 0 {
+            (tree.insert(key), oracle.insert(key))
+        } else {
+            (tree.delete(key), oracle.remove(&key))
+        };
+        if changed != expected || tree.contains(key) != oracle.contains(&key) || tree.len() != oracle.len() {
+            result = Err(format!("writer step {}: key {} disagrees with the oracle", step, key));
+            break;
+        }
+    }
+    done.store(true, Ordering::Release);
+    let mut rounds = 0;
+    for thread in reader_threads {
+        rounds += thread.join().map_err(|_| "reader panicked".to_string())??;
+    }
+    result?;
+
+    let before = tree.snapshot();
+    before.validate().map_err(|v| format!("final tree is broken: {}", v))?;
+    if !before.iter().eq(oracle.iter().cloned()) {
+        return Err("final contents differ from the oracle".to_string());
+    }
+    // Bản chụp in ra đúng định dạng preorder nên đọc lại được
+    let shape = before.preorder_string();
+    let parsed = parse_tree(&shape).map_err(|e| format!("final version does not parse back: {}", e))?;
+    if preorder_string(parsed.as_ref()) != shape {
+        return Err("final version changed shape on a round trip".to_string());
+    }
+    let fresh = (0..).map(|k| 5_000 + k).find(|k| !before.contains(*k)).unwrap();
+    tree.insert(fresh);
+    let after = tree.snapshot();
+    println!("ok: {} writes, {} reader rounds over {} threads; one more insert shares {} of {} nodes",
+        ops, rounds, readers, after.shared_nodes(&before), p_count(&after.root));
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VersionError {
+    Reclaimed { version: u64, oldest: u64 },
+    NotYetWritten { version: u64, latest: u64 },
+}
+
+impl std::fmt::Display for VersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VersionError::Reclaimed { version, oldest } =>
+                write!(f, "version {} was reclaimed, the oldest kept is {}", version, oldest),
+            VersionError::NotYetWritten { version, latest } =>
+                write!(f, "version {} does not exist yet, the latest is {}", version, latest),
+        }
+    }
+}
+
+/// Keeps every committed version of the tree. Version 0 is the empty tree
+/// and each insert or delete that changes something commits the next one.
+/// Versions are `Snapshot`s, so consecutive ones share all but one path of
+/// nodes and a version costs O(log n) memory; `gc` drops whole versions and
+/// the nodes only they were using.
+#[derive(Debug, Default)]
+struct VersionedRBTree {
+    // oldest kept version first, the latest last; never empty
+    history: std::collections::VecDeque<Snapshot>,
+}
+
+impl VersionedRBTree {
+    fn new() -> VersionedRBTree {
+        VersionedRBTree { history: std::iter::once(Snapshot::default()).collect() }
+    }
+
+    fn latest(&self) -> &Snapshot {
+        self.history.back().unwrap()
+    }
+
+    fn latest_version(&self) -> u64 {
+        self.latest().version()
+    }
+
+    fn oldest_version(&self) -> u64 {
+        self.history.front().unwrap().version()
+    }
+
+    fn contains(&self, key: i32) -> bool {
+        self.latest().contains(key)
+    }
+
+    fn len(&self) -> usize {
+        self.latest().len()
+    }
+
+    // Returns true (and commits a version) when `x` was not there yet
+    fn insert(&mut self, x: i32) -> bool {
+        let next = self.latest().inserted(x);
+        self.commit(next)
+    }
+
+    // Returns true (and commits a version) when `key` was there
+    fn delete(&mut self, key: i32) -> bool {
+        let next = self.latest().deleted(key);
+        self.commit(next)
+    }
+
+    fn commit(&mut self, next: Option<Snapshot>) -> bool {
+        match next {
+            None => false,
+            Some(snapshot) => {
+                self.history.push_back(snapshot);
+                true
+            },
+        }
+    }
+
+    // The tree as it was right after commit `version`
+    fn at(&self, version: u64) -> Result<&Snapshot, VersionError> {
+        let (oldest, latest) = (self.oldest_version(), self.latest_version());
+        if version < oldest {
+            return Err(VersionError::Reclaimed { version, oldest });
+        }
+        if version > latest {
+            return Err(VersionError::NotYetWritten { version, latest });
+        }
+        // versions are consecutive, so the position is the offset from the oldest
+        Ok(&self.history[(version - oldest) as usize])
+    }
+
+    // The stored key if `key` was in the tree at `version`
+    fn get_at(&self, version: u64, key: &i32) -> Result<Option<i32>, VersionError> {
+        let snapshot = self.at(version)?;
+        Ok(if snapshot.contains(*key) { Some(*key) } else { None })
+    }
+
+    // Keys in [low, high] at `version`; the iterator outlives a later `gc`
+    fn range_at(&self, version: u64, low: i32, high: i32) -> Result<SnapshotIter, VersionError> {
+        self.at(version).map(|snapshot| snapshot.range(low, high))
+    }
+
+    // Drops every version before `older_than`, always keeping the latest.
+    // Returns how many versions went.
+    fn gc(&mut self, older_than: u64) -> usize {
+        let keep_from = older_than.min(self.latest_version());
+        let drop = keep_from.saturating_sub(self.oldest_version()) as usize;
+        self.history.drain(..drop);
+        drop
+    }
+
+    fn version_count(&self) -> usize {
+        self.history.len()
+    }
+}
+
+/// `rbtree history`: commits random writes to a `VersionedRBTree` while
+/// recording every version's contents, then checks as-of reads of every kept
+/// version before and after several `gc` calls.
+fn history_check(ops: usize, seed: u64) -> Result<(), String> {
+    let mut tree = VersionedRBTree::new();
+    let mut oracle = std::collections::BTreeSet::new();
+    // expected[v] is the contents at version v
+    let mut expected = vec![Vec::new()];
+    let mut rng = Rng(seed);
+    for step in 0..ops {
+        let key = rng.between(0, 999);
+        let (changed, wanted) = if rng.below(2) == 0 {
+            (tree.insert(key), oracle.insert(key))
+        } else {
+            (tree.delete(key), oracle.remove(&key))
+        };
+        if changed != wanted || tree.contains(key) != oracle.contains(&key) {
+            return Err(format!("step {}: key {} disagrees with the oracle", step, key));
+        }
+        if changed {
+            expected.push(oracle.iter().cloned().collect());
+        }
+    }
+    if tree.latest_version() + 1 != expected.len() as u64 {
+        return Err(format!("{} versions committed for {} changes", tree.latest_version(), expected.len() - 1));
+    }
+
+    let check_kept = |tree: &VersionedRBTree, rng: &mut Rng| -> Result<(), String> {
+        for version in tree.oldest_version()..=tree.latest_version() {
+            let keys = &expected[version as usize];
+            let snapshot = tree.at(version).map_err(|e| e.to_string())?;
+            snapshot.validate().map_err(|v| format!("version {} is broken: {}", version, v))?;
+            let (low, high) = (rng.between(0, 999), rng.between(0, 999));
+            let (low, high) = (low.min(high), low.max(high));
+            let listed: Vec<i32> = tree.range_at(version, low, high).map_err(|e| e.to_string())?.collect();
+            let wanted: Vec<i32> = keys.iter().cloned().filter(|k| (low..=high).contains(k)).collect();
+            if listed != wanted {
+                return Err(format!("range_at({}, {}, {}) gave {:?}, expected {:?}", version, low, high, listed, wanted));
+            }
+            let key = rng.between(0, 999);
+            let found = tree.get_at(version, &key).map_err(|e| e.to_string())?;
+            if found.is_some() != keys.binary_search(&key).is_ok() {
+                return Err(format!("get_at({}, {}) gave {:?}", version, key, found));
+            }
+        }
+        Ok(())
+    };
+
+    check_kept(&tree, &mut rng)?;
+    let total = tree.latest_version();
+    let mut reclaimed = 0;
+    for cut in &[total / 4, total / 2, total / 2, total + 10] {
+        reclaimed += tree.gc(*cut);
+        let oldest = tree.oldest_version();
+        if oldest != (*cut).min(total) {
+            return Err(format!("gc({}) left the oldest version at {}", cut, oldest));
+        }
+        if oldest > 0 && tree.get_at(oldest - 1, &0) != Err(VersionError::Reclaimed { version: oldest - 1, oldest }) {
+            return Err(format!("version {} still answers after gc({})", oldest - 1, cut));
+        }
+        check_kept(&tree, &mut rng)?;
+    }
+    if tree.version_count() != 1 || tree.at(total + 1).is_ok() {
+        return Err("gc past the latest version must keep exactly the latest".to_string());
+    }
+    println!("ok: {} writes, {} versions committed, {} reclaimed, {} keys now", ops, total, reclaimed, tree.len());
+    Ok(())
+}
+
+/// One completed call in a concurrent history. `invoked` and `returned`
+/// come from a shared counter, so `a.returned < b.invoked` means `a`
+/// really finished before `b` started.
+#[derive(Debug, Clone, PartialEq)]
+struct HistoryCall {
+    thread: usize,
+    op: Command,
+    result: bool,
+    invoked: u64,
+    returned: u64,
+}
+
+fn call_key(op: &Command) -> i32 {
+    match *op {
+        Command::Insert(x) | Command::Delete(x) | Command::Find(x) => x,
+        _ => panic!("only insert, delete and find go into a history"),
+    }
+}
+
+/// Checks that a history of set operations is linearizable: some order that
+/// respects real time (a call that returned before another started comes
+/// first) explains every result with a sequential set. Keys never interact,
+/// so each key is checked on its own (Wing & Gong search with memoisation).
+fn check_linearizable(history: &[HistoryCall]) -> Result<(), String> {
+    let mut by_key: std::collections::BTreeMap<i32, Vec<&HistoryCall>> = std::collections::BTreeMap::new();
+    for call in history {
+        by_key.entry(call_key(&call.op)).or_default().push(call);
+    }
+    for (key, mut calls) in by_key {
+        calls.sort_by_key(|c| c.invoked);
+        let mut done = vec![false; calls.len()];
+        let mut seen = std::collections::HashSet::new();
+        if !linearize_key(&calls, &mut done, false, &mut seen) {
+            let listing: Vec<String> = calls.iter()
+                .map(|c| format!("  thread {} {:?} -> {} [{}, {}]", c.thread, c.op, c.result, c.invoked, c.returned))
+                .collect();
+            return Err(format!("history for key {} is not linearizable:\n{}", key, listing.join("\n")));
+        }
+    }
+    Ok(())
+}
+
+fn linearize_key(calls: &[&HistoryCall], done: &mut Vec<bool>, present: bool,
+        seen: &mut std::collections::HashSet<(Vec<bool>, bool)>) -> bool {
+    let first_open = match done.iter().position(|d| !d) {
+        None => return true,
+        Some(i) => i,
+    };
+    if !seen.insert((done.clone(), present)) {
+        return false;
+    }
+    // Chỉ những lời gọi bắt đầu trước khi lời gọi sớm nhất còn lại kết thúc mới có thể đứng đầu
+    let deadline = calls.iter().zip(done.iter()).filter(|(_, d)| !**d).map(|(c, _)| c.returned).min().unwrap();
+    for i in first_open..calls.len() {
+        if done[i] {
+            continue;
+        }
+        if calls[i].invoked > deadline {
+            break;
+        }
+        let (expected, next) = match calls[i].op {
+            Command::Insert(_) => (!present, true),
+            Command::Delete(_) => (present, false),
+            _ => (present, present),
+        };
+        if calls[i].result != expected {
+            continue;
+        }
+        done[i] = true;
+        if linearize_key(calls, done, next, seen) {
+            return true;
+        }
+        done[i] = false;
+    }
+    false
+}
+
+/// `rbtree linearizability`: `threads` threads run `ops` random operations
+/// each on a shared `ConcurrentRBTree` over `keys` keys, then the recorded
+/// history goes through `check_linearizable` and the tree is validated.
+fn linearizability_run(threads: usize, ops: usize, keys: i32, seed: u64) -> Result<(), String> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ConcurrentRBTree>();
+
+    let tree = Arc::new(ConcurrentRBTree::new());
+    let clock = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..threads).map(|thread| {
+        let tree = tree.clone();
+        let clock = clock.clone();
+        std::thread::spawn(move || {
+            let mut rng = Rng(seed ^ ((thread as u64 + 1) << 32));
+            let mut calls = Vec::with_capacity(ops);
+            for _ in 0..ops {
+                let key = rng.between(0, keys - 1);
+                let op = match rng.below(3) {
+                    0 => Command::Insert(key),
+                    1 => Command::Delete(key),
+                    _ => Command::Find(key),
+                };
+                let invoked = clock.fetch_add(1, Ordering::SeqCst);
+                let result = match op {
+                    Command::Insert(x) => tree.insert(x),
+                    Command::Delete(x) => tree.delete(x),
+                    _ => tree.contains(key),
+                };
+                let returned = clock.fetch_add(1, Ordering::SeqCst);
+                calls.push(HistoryCall { thread, op, result, invoked, returned });
+            }
+            calls
+        })
+    }).collect();
+
+    let mut history = Vec::new();
+    for worker in workers {
+        history.extend(worker.join().map_err(|_| "worker panicked".to_string())?);
+    }
+    check_linearizable(&history)?;
+    tree.validate().map_err(|v| format!("final tree is broken: {}", v))?;
+    if tree.keys().len() != tree.len() {
+        return Err(format!("len {} but {} keys", tree.len(), tree.keys().len()));
+    }
+    println!("ok: {} calls from {} threads are linearizable, {} keys left", history.len(), threads, tree.len());
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Insert(i32),
+    Delete(i32),
+    Find(i32),
+    Range(i32, i32),
+    Show(Layout),
+    Check,
+    Dump,
+    Shape,
+    Stats { reset: bool },
+    Undo,
+    Redo,
+    Checkpoint(String),
+    Rollback(String),
+    Trace(bool),
+    Save(String),
+    Load(String),
+    Svg(String),
+    Dot(String, Option<i32>),
+    Help,
+    Quit,
+}
+
+const REPL_HELP: &str = "\
+insert N | delete N | find N | range LOW HIGH
+show [side|top] | check | dump | shape | stats [reset] | trace on|off
+undo | redo | checkpoint NAME | rollback NAME
+save FILE | load FILE | svg FILE | dot FILE [KEY] | help | quit
+load replaces the tree and forgets the undo history and checkpoints
+scripts may also use +N, -N and ?N for insert, delete and find";
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |word: &str| word.parse::<i32>().map_err(|_| format!("not a number: {:?}", word));
+        if let [word] = words.as_slice() {
+            if let Some(x) = word.strip_prefix('+') {
+                return Ok(Command::Insert(number(x)?));
+            }
+            if let Some(x) = word.strip_prefix('-').filter(|x| !x.is_empty()) {
+                return Ok(Command::Delete(number(x)?));
+            }
+            if let Some(x) = word.strip_prefix('?') {
+                return Ok(Command::Find(number(x)?));
+            }
+        }
+        match words.as_slice() {
+            ["insert", x] => Ok(Command::Insert(number(x)?)),
+            ["delete", x] => Ok(Command::Delete(number(x)?)),
+            ["find", x] => Ok(Command::Find(number(x)?)),
+            ["range", low, high] => Ok(Command::Range(number(low)?, number(high)?)),
+            ["show"] | ["show", "side"] => Ok(Command::Show(Layout::Sideways)),
+            ["show", "top"] => Ok(Command::Show(Layout::TopDown)),
+            ["check"] => Ok(Command::Check),
+            ["dump"] => Ok(Command::Dump),
+            ["shape"] => Ok(Command::Shape),
+            ["stats"] => Ok(Command::Stats { reset: false }),
+            ["stats", "reset"] => Ok(Command::Stats { reset: true }),
+            ["undo"] => Ok(Command::Undo),
+            ["redo"] => Ok(Command::Redo),
+            ["checkpoint", name] => Ok(Command::Checkpoint(name.to_string())),
+            ["rollback", name] => Ok(Command::Rollback(name.to_string())),
+            ["trace", "on"] => Ok(Command::Trace(true)),
+            ["trace", "off"] => Ok(Command::Trace(false)),
+            ["save", file] => Ok(Command::Save(file.to_string())),
+            ["load", file] => Ok(Command::Load(file.to_string())),
+            ["svg", file] => Ok(Command::Svg(file.to_string())),
+            ["dot", file] => Ok(Command::Dot(file.to_string(), None)),
+            ["dot", file, key] => Ok(Command::Dot(file.to_string(), Some(number(key)?))),
+            ["help"] => Ok(Command::Help),
+            ["quit"] | ["exit"] => Ok(Command::Quit),
+            _ => Err(format!("unknown command {:?}, try `help`", line.trim())),
+        }
+    }
+}
+
+/// Marks a point in an `UndoableRBTree`'s history to roll back to. It stays
+/// valid across undo and redo, and goes stale once the operation it points
+/// after is undone and then replaced by a new write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Checkpoint {
+    // sequence number of the newest applied operation, 0 for "nothing applied"
+    after: u64,
+}
+
+/// An `RBTree` that remembers how to reverse its writes. Each insert or
+/// delete that changes the tree is recorded, and `undo` applies its inverse
+/// (a delete for an insert and the other way round). This tree holds keys
+/// only, so a delete loses nothing that the inverse insert cannot put back.
+/// Undo restores the same keys but the rebalancing may leave a different
+/// shape than before. Every undo and redo is checked with a full `validate`,
+/// and one that would leave a broken tree is refused with the tree put back
+/// exactly as it was, shape and colours included.
+struct UndoableRBTree {
+    tree: RBTree,
+    // applied operations with their sequence numbers, newest last
+    done: Vec<(u64, Operation)>,
+    // undone operations, the next one to redo last; cleared by a new write
+    undone: Vec<(u64, Operation)>,
+    next_seq: u64,
+}
+
+impl UndoableRBTree {
+    fn new() -> UndoableRBTree {
+        UndoableRBTree { tree: RBTree::new(), done: Vec::new(), undone: Vec::new(), next_seq: 1 }
+    }
+
+    fn tree(&self) -> &RBTree {
+        &self.tree
+    }
+
+    // For settings and counters; key changes made through it are not recorded
+    fn tree_mut(&mut self) -> &mut RBTree {
+        &mut self.tree
+    }
+
+    // Puts in a whole new tree and forgets the history, which cannot undo that
+    fn replace_root(&mut self, root: Option<NodeRef>) {
+        self.tree.root = root;
+        self.done.clear();
+        self.undone.clear();
+    }
+
+    // Returns true (and records the write) when `x` was not there yet
+    fn insert(&mut self, x: i32) -> bool {
+        self.tree.insert(x) && self.record(Operation::Insert(x))
+    }
+
+    // Returns true (and records the write) when `key` was there
+    fn delete(&mut self, key: i32) -> bool {
+        self.tree.delete(key) && self.record(Operation::Delete(key))
+    }
+
+    // Called after `op` has changed the tree
+    fn record(&mut self, op: Operation) -> bool {
+        self.done.push((self.next_seq, op));
+        self.next_seq += 1;
+        self.undone.clear();
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    // Applies `op` if that leaves a valid tree. Otherwise returns the
+    // violation with the tree exactly as before; a tree that is already
+    // broken is not touched at all, since fixups on it can do anything.
+    fn apply_checked(&mut self, op: Operation) -> Result<(), Violation> {
+        validate(self.tree.root.as_ref())?;
+        let before = preorder_string(self.tree.root.as_ref());
+        self.tree.apply(op);
+        if let Err(violation) = validate(self.tree.root.as_ref()) {
+            self.tree.root = parse_tree_unchecked(&before).expect("a valid tree reads back from its preorder");
+            return Err(violation);
+        }
+        Ok(())
+    }
+
+    // Reverses the newest applied operation and returns it. On a violation
+    // the entry stays for another try.
+    fn undo(&mut self) -> Result<Option<Operation>, Violation> {
+        let (seq, op) = match self.done.last() {
+            Some(&entry) => entry,
+            None => return Ok(None),
+        };
+        self.apply_checked(op.inverse())?;
+        self.done.pop();
+        self.undone.push((seq, op));
+        Ok(Some(op))
+    }
+
+    // Applies the most recently undone operation again and returns it. On a
+    // violation the entry stays for another try.
+    fn redo(&mut self) -> Result<Option<Operation>, Violation> {
+        let (seq, op) = match self.undone.last() {
+            Some(&entry) => entry,
+            None => return Ok(None),
+        };
+        self.apply_checked(op)?;
+        self.undone.pop();
+        self.done.push((seq, op));
+        Ok(Some(op))
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { after: self.done.last().map_or(0, |&(seq, _)| seq) }
+    }
+
+    // Undoes or redoes until the tree is back at `checkpoint`. Returns how
+    // many operations moved, or None (with nothing changed) when the
+    // checkpoint's history has been overwritten. A failed step stops the
+    // rollback part way: the steps before it stay done, so the tree is valid
+    // but between where it was and `checkpoint`, and `checkpoint()` says where.
+    fn rollback_to(&mut self, checkpoint: Checkpoint) -> Result<Option<usize>, Violation> {
+        let is_at = |entry: &(u64, Operation)| entry.0 == checkpoint.after;
+        let mut moved = 0;
+        if checkpoint.after == 0 || self.done.iter().any(is_at) {
+            while self.checkpoint() != checkpoint {
+                self.undo()?;
+                moved += 1;
+            }
+        } else if self.undone.iter().any(is_at) {
+            while self.checkpoint() != checkpoint {
+                self.redo()?;
+                moved += 1;
+            }
+        } else {
+            return Ok(None);
+        }
+        Ok(Some(moved))
+    }
+}
+
+/// `rbtree undo`: random writes, undos, redos and checkpoint rollbacks on an
+/// `UndoableRBTree`, each checked against the key set it should be back at.
+fn undo_check(ops: usize, seed: u64) -> Result<(), String> {
+    let mut tree = UndoableRBTree::new();
+    // key sets matching `done` and `undone`: history[i] is the set after i operations
+    let mut history = vec![std::collections::BTreeSet::new()];
+    let mut position: usize = 0;
+    // each checkpoint with its position and whether a later write overwrote it
+    let mut checkpoints: Vec<(Checkpoint, usize, bool)> = Vec::new();
+    let mut rng = Rng(seed);
+    let (mut undos, mut redos, mut rollbacks) = (0, 0, 0);
+    for step in 0..ops {
+        match rng.below(10) {
+            0..=1 => {
+                let op = tree.undo().map_err(|v| format!("step {}: undo broke the tree: {}", step, v))?;
+                if op.is_some() != (position > 0) {
+                    return Err(format!("step {}: undo returned {:?} at position {}", step, op, position));
+                }
+                position = position.saturating_sub(1);
+                undos += op.is_some() as usize;
+            },
+            2 => {
+                let op = tree.redo().map_err(|v| format!("step {}: redo broke the tree: {}", step, v))?;
+                if op.is_some() != (position + 1 < history.len()) {
+                    return Err(format!("step {}: redo returned {:?} at position {}", step, op, position));
+                }
+                position += op.is_some() as usize;
+                redos += op.is_some() as usize;
+            },
+            3 => checkpoints.push((tree.checkpoint(), position, false)),
+            4 if !checkpoints.is_empty() => {
+                let (checkpoint, at, stale) = checkpoints[rng.below(checkpoints.len() as u64) as usize];
+                let moved = tree.rollback_to(checkpoint).map_err(|v| format!("step {}: rollback broke the tree: {}", step, v))?;
+                let expected = if stale { None } else { Some((position as isize - at as isize).unsigned_abs()) };
+                if moved != expected {
+                    return Err(format!("step {}: rollback from {} to {} moved {:?}, expected {:?}", step, position, at, moved, expected));
+                }
+                if !stale {
+                    position = at;
+                    rollbacks += 1;
+                }
+            },
+            _ => {
+                let key = rng.between(0, 199);
+                let mut keys = history[position].clone();
+                let (changed, expected) = if rng.below(2) == 0 {
+                    (tree.insert(key), keys.insert(key))
+                } else {
+                    (tree.delete(key), keys.remove(&key))
+                };
+                if changed != expected {
+                    return Err(format!("step {}: key {} disagrees with the oracle", step, key));
+                }
+                if changed {
+                    for checkpoint in checkpoints.iter_mut().filter(|c| c.1 > position) {
+                        checkpoint.2 = true;
+                    }
+                    history.truncate(position + 1);
+                    history.push(keys);
+                    position += 1;
+                }
+            },
+        }
+        if !tree.tree().keys().iter().eq(history[position].iter()) {
+            return Err(format!("step {}: tree holds {:?}, expected {:?}", step, tree.tree().keys(), history[position]));
+        }
+        if tree.can_undo() != (position > 0) || tree.can_redo() != (position + 1 < history.len()) {
+            return Err(format!("step {}: undo/redo availability is wrong at position {}", step, position));
+        }
+    }
+    validate(tree.tree().root.as_ref()).map_err(|v| format!("final tree is broken: {}", v))?;
+    println!("ok: {} steps, {} undos, {} redos, {} rollbacks, {} keys now", ops, undos, redos, rollbacks, tree.tree().len());
+    Ok(())
+}
+
+/// An `UndoableRBTree` driven one `Command` at a time, with the named
+/// checkpoints the REPL has set.
+struct Session {
+    tree: UndoableRBTree,
+    checkpoints: std::collections::BTreeMap<String, Checkpoint>,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session { tree: UndoableRBTree::new(), checkpoints: std::collections::BTreeMap::new() }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Insert(x) => {
+                if !self.tree.insert(x) {
+                    return Err(format!("{} is already in the tree", x));
+                }
+                Ok(format!("inserted {}", x))
+            },
+            Command::Delete(key) => {
+                if !self.tree.delete(key) {
+                    return Err(format!("{} is not in the tree", key));
+                }
+                Ok(format!("deleted {}", key))
+            },
+            Command::Find(key) => match self.tree.tree().find(key) {
+                Some(node) => {
+                    let color = if node.borrow().color == Color::Red { "red" } else { "black" };
+                    Ok(format!("found {} ({})", key, color))
+                },
+                None => Ok(format!("{} not found", key)),
+            },
+            Command::Range(low, high) => {
+                let keys: Vec<String> = self.tree.tree().range(low, high).iter().map(|k| k.to_string()).collect();
+                Ok(if keys.is_empty() { "(none)".to_string() } else { keys.join(" ") })
+            },
+            Command::Show(Layout::Sideways) => Ok(self.tree.tree().to_string().trim_end().to_string()),
+            Command::Show(Layout::TopDown) => Ok(format!("{:#}", self.tree.tree()).trim_end().to_string()),
+            Command::Check => match validate(self.tree.tree().root.as_ref()) {
+                Ok(black_height) => Ok(format!("ok, black height {}", black_height)),
+                Err(violation) => Err(violation.to_string()),
+            },
+            Command::Dump => Ok(preorder_string(self.tree.tree().root.as_ref()).trim_end().to_string()),
+            Command::Shape => Ok(self.tree.tree().shape_stats().to_string()),
+            #[cfg(feature = "stats")]
+            Command::Stats { reset } => {
+                let report = self.tree.tree().stats().to_string();
+                if reset {
+                    self.tree.tree_mut().reset_stats();
+                }
+                Ok(report)
+            },
+            #[cfg(not(feature = "stats"))]
+            Command::Stats { .. } => Err("cost counters are not compiled in, rebuild with --cfg 'feature=\"stats\"'".to_string()),
+            Command::Undo => match self.tree.undo() {
+                Ok(Some(op)) => Ok(format!("undid {}", op)),
+                Ok(None) => Err("nothing to undo".to_string()),
+                Err(violation) => Err(format!("undo would break the tree, kept it as it was: {}", violation)),
+            },
+            Command::Redo => match self.tree.redo() {
+                Ok(Some(op)) => Ok(format!("redid {}", op)),
+                Ok(None) => Err("nothing to redo".to_string()),
+                Err(violation) => Err(format!("redo would break the tree, kept it as it was: {}", violation)),
+            },
+            Command::Checkpoint(name) => {
+                self.checkpoints.insert(name.clone(), self.tree.checkpoint());
+                Ok(format!("checkpoint {}", name))
+            },
+            Command::Rollback(name) => {
+                let checkpoint = *self.checkpoints.get(&name).ok_or_else(|| format!("no checkpoint named {}", name))?;
+                match self.tree.rollback_to(checkpoint) {
+                    Ok(Some(moved)) => Ok(format!("rolled back to {} ({} operations)", name, moved)),
+                    Ok(None) => Err(format!("checkpoint {} was overwritten by later writes", name)),
+                    Err(violation) => Err(format!("rollback stopped at a broken tree: {}", violation)),
+                }
+            },
+            Command::Trace(on) => {
+                self.tree.tree_mut().set_logger(if on { Logger::stdout(LogLevel::Cases) } else { Logger::off() });
+                Ok(format!("trace {}", if on { "on" } else { "off" }))
+            },
+            Command::Save(file) => {
+                std::fs::write(&file, preorder_string(self.tree.tree().root.as_ref()))
+                    .map_err(|e| format!("cannot write {}: {}", file, e))?;
+                Ok(format!("saved to {}", file))
+            },
+            Command::Load(file) => {
+                let text = std::fs::read_to_string(&file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+                let root = parse_tree(&text).map_err(|e| format!("{}:{}", file, e))?;
+                self.tree.replace_root(root);
+                self.checkpoints.clear();
+                Ok(format!("loaded {}", file))
+            },
+            Command::Svg(file) => {
+                write_svg(self.tree.tree().root.as_ref(), std::path::Path::new(&file))
+                    .map_err(|e| format!("cannot write {}: {}", file, e))?;
+                Ok(format!("drew {} nodes to {}", self.tree.tree().len(), file))
+            },
+            Command::Dot(file, highlight) => {
+                let options = DotOptions { highlight, ..DotOptions::new() };
+                std::fs::write(&file, self.tree.tree().to_dot(options))
+                    .map_err(|e| format!("cannot write {}: {}", file, e))?;
+                Ok(format!("wrote {} nodes to {}", self.tree.tree().len(), file))
+            },
+            Command::Help => Ok(REPL_HELP.to_string()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+}
+
+/// Interactive prompt, started with `rbtree repl`. Trees are saved and
+/// loaded in the `preorder_string` format.
+fn run_repl() {
+    use std::io::{BufRead, Write};
+    let mut session = Session::new();
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("rbtree> ");
+        std::io::stdout().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Command::parse(&line) {
+            Ok(Command::Quit) => break,
+            Ok(command) => match session.execute(command) {
+                Ok(output) => println!("{}", output),
+                Err(message) => println!("error: {}", message),
+            },
+            Err(message) => println!("error: {}", message),
+        }
+    }
+    println!();
+}
+
+/// Runs a scenario script, one command per line (`+50`, `-30`, `?55`,
+/// `check`, `dump` or anything the REPL accepts); `#` starts a comment.
+/// Every line is reported as `N: command -> result`. Returns the exit code:
+/// 0 when every line succeeded, 1 when a command failed (duplicate insert,
+/// missing key, `check` violation, ...), 2 when the script has a syntax error.
+/// Syntax errors stop the script before anything runs.
+fn run_script(text: &str) -> i32 {
+    let mut commands = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Command::parse(line) {
+            Ok(command) => commands.push((number + 1, line, command)),
+            Err(message) => {
+                eprintln!("{}: {}", number + 1, message);
+                return 2;
+            },
+        }
+    }
+
+    let mut session = Session::new();
+    let mut failures = 0;
+    for (number, line, command) in commands {
+        if command == Command::Quit {
+            break;
+        }
+        match session.execute(command) {
+            Ok(output) => println!("{}: {} -> {}", number, line, output),
+            Err(message) => {
+                failures += 1;
+                println!("{}: {} -> error: {}", number, line, message);
+            },
+        }
+    }
+    if failures > 0 {
+        eprintln!("{} command(s) failed", failures);
+        1
+    } else {
+        0
+    }
+}
+
+// splitmix64: đủ tốt cho sinh dữ liệu kiểm thử và không cần thư viện ngoài
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn between(&mut self, low: i32, high: i32) -> i32 {
+        (low as i64 + self.below((high as i64 - low as i64 + 1) as u64) as i64) as i32
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StressConfig {
+    seed: u64,
+    ops: usize,
+    // relative weights of insert, delete and find
+    mix: (u32, u32, u32),
+    key_low: i32,
+    key_high: i32,
+    successor: i32,
+}
+
+impl StressConfig {
+    fn new() -> StressConfig {
+        StressConfig { seed: 1, ops: 10_000, mix: (60, 30, 10), key_low: 0, key_high: 999, successor: 0 }
+    }
+
+    /// Reads `--seed N --ops M --mix insert:60,delete:30,find:10
+    /// --key-range LOW..HIGH --successor 0|1`; anything left out keeps its default.
+    fn parse(args: &[String]) -> Result<StressConfig, String> {
+        let mut config = StressConfig::new();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            let bad = || format!("bad value for {}: {:?}", flag, value);
+            match flag.as_str() {
+                "--seed" => config.seed = value.parse().map_err(|_| bad())?,
+                "--ops" => config.ops = value.parse().map_err(|_| bad())?,
+                "--successor" => config.successor = value.parse().map_err(|_| bad())?,
+                "--key-range" => {
+                    let (low, high) = value.split_once("..").ok_or_else(bad)?;
+                    config.key_low = low.parse().map_err(|_| bad())?;
+                    config.key_high = high.parse().map_err(|_| bad())?;
+                    if config.key_low > config.key_high {
+                        return Err(bad());
+                    }
+                },
+                "--mix" => {
+                    config.mix = (0, 0, 0);
+                    for part in value.split(',') {
+                        let (name, weight) = part.split_once(':').ok_or_else(bad)?;
+                        let weight: u32 = weight.parse().map_err(|_| bad())?;
+                        match name {
+                            "insert" => config.mix.0 = weight,
+                            "delete" => config.mix.1 = weight,
+                            "find" => config.mix.2 = weight,
+                            _ => return Err(bad()),
+                        }
+                    }
+                    if config.mix.0 + config.mix.1 + config.mix.2 == 0 {
+                        return Err(bad());
+                    }
+                },
+                _ => return Err(format!("unknown option {:?}", flag)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Builds the deterministic workload for `config`. Deletes usually pick a key
+/// that is present so they reach the fixup code instead of missing.
+fn generate_workload(config: &StressConfig) -> Vec<Command> {
+    let mut rng = Rng(config.seed);
+    let mut present = std::collections::BTreeSet::new();
+    let (insert, delete, find) = config.mix;
+    let mut commands = Vec::with_capacity(config.ops);
+    for _ in 0..config.ops {
+        let roll = rng.below((insert + delete + find) as u64) as u32;
+        let key = rng.between(config.key_low, config.key_high);
+        let command = if roll < insert {
+            Command::Insert(key)
+        } else if roll < insert + delete {
+            if !present.is_empty() && rng.below(4) != 0 {
+                let nth = rng.below(present.len() as u64) as usize;
+                Command::Delete(*present.iter().nth(nth).unwrap())
+            } else {
+                Command::Delete(key)
+            }
+        } else {
+            Command::Find(key)
+        };
+        match command {
+            Command::Insert(x) => { present.insert(x); },
+            Command::Delete(x) => { present.remove(&x); },
+            _ => {},
+        }
+        commands.push(command);
+    }
+    commands
+}
+
+/// Runs `commands` against a fresh tree and a `BTreeSet`, checking the
+/// red-black rules, the in-order contents and every lookup after each step.
+/// Returns the index of the failing command and what went wrong. Panics inside
+/// the tree count as failures.
+fn check_workload(commands: &[Command], successor: i32) -> Result<(), (usize, String)> {
+    let mut oracle = std::collections::BTreeSet::new();
+    let mut tree = RBTree::new();
+    tree.set_paranoid(false);
+    tree.set_successor(successor);
+
+    for (index, command) in commands.iter().enumerate() {
+        let step = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            match *command {
+                Command::Insert(x) => {
+                    if tree.contains(x) != oracle.contains(&x) {
+                        return Err(format!("find {} before insert disagrees with the oracle", x));
+                    }
+                    if tree.insert(x) != oracle.insert(x) {
+                        return Err(format!("insert {} disagrees with the oracle", x));
+                    }
+                },
+                Command::Delete(key) => {
+                    if tree.contains(key) != oracle.contains(&key) {
+                        return Err(format!("find {} before delete disagrees with the oracle", key));
+                    }
+                    if tree.delete(key) != oracle.remove(&key) {
+                        return Err(format!("delete {} disagrees with the oracle", key));
+                    }
+                },
+                Command::Find(key) if tree.contains(key) != oracle.contains(&key) => {
+                    return Err(format!("find {} returned {}, oracle says {}", key, tree.contains(key), oracle.contains(&key)));
+                },
+                _ => {},
+            }
+            validate(tree.root.as_ref()).map_err(|violation| violation.to_string())?;
+            let keys = tree.range(i32::MIN, i32::MAX);
+            if !keys.iter().eq(oracle.iter()) {
+                return Err(format!("tree holds {} keys, oracle holds {}; contents differ", keys.len(), oracle.len()));
+            }
+            Ok(())
+        }));
+        match step {
+            Ok(Ok(())) => {},
+            Ok(Err(message)) => return Err((index, message)),
+            Err(payload) => {
+                let message = payload.downcast_ref::<String>().cloned()
+                    .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                return Err((index, format!("panicked: {}", message)));
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Shrinks a failing workload by deleting chunks of commands, halving the
+/// chunk size until single commands no longer go, and keeping every cut
+/// that still fails. `check` reports the index of the failing command, like
+/// `check_workload`; everything after it is dropped.
+fn shrink_workload<F>(mut commands: Vec<Command>, check: F) -> Vec<Command>
+where
+    F: Fn(&[Command]) -> Result<(), (usize, String)>,
+{
+    if let Err((index, _)) = check(&commands) {
+        commands.truncate(index + 1);
+    }
+    let mut chunk = commands.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        while start < commands.len() {
+            let mut candidate = commands.clone();
+            candidate.drain(start..(start + chunk).min(commands.len()));
+            match check(&candidate) {
+                Err((index, _)) => {
+                    candidate.truncate(index + 1);
+                    commands = candidate;
+                },
+                Ok(()) => start += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+    commands
+}
+
+fn script_line(command: &Command) -> String {
+    match command {
+        Command::Insert(x) => format!("+{}", x),
+        Command::Delete(x) => format!("-{}", x),
+        Command::Find(x) => format!("?{}", x),
+        _ => unreachable!("workloads only hold insert, delete and find"),
+    }
+}
+
+/// `rbtree stress ...`: returns 0 when the workload passes, 1 after printing a
+/// shrunk reproducer in the `run_script` format.
+fn run_stress(config: &StressConfig) -> i32 {
+    let commands = generate_workload(config);
+    // Các panic được bắt lại, không cần in ra từng cái khi thu gọn
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = check_workload(&commands, config.successor);
+    let outcome = match result {
+        Ok(()) => {
+            println!("ok: {} operations, seed {}", commands.len(), config.seed);
+            0
+        },
+        Err((index, message)) => {
+            println!("# failed at operation {} of seed {}: {}", index + 1, config.seed, message);
+            let reproducer = shrink_workload(commands, |c| check_workload(c, config.successor));
+            let (_, message) = check_workload(&reproducer, config.successor).unwrap_err();
+            println!("# shrunk to {} operations (successor {}): {}", reproducer.len(), config.successor, message);
+            for command in &reproducer {
+                println!("{}", script_line(command));
+            }
+            println!("check");
+            1
+        },
+    };
+    std::panic::set_hook(hook);
+    outcome
+}
+
+const INSERT_CASES: [InsertCase; 5] = [InsertCase::UncleRed, InsertCase::LL, InsertCase::LR, InsertCase::RR, InsertCase::RL];
+const DELETE_CASES: [DeleteCase; 4] = [
+    DeleteCase::SiblingRed,
+    DeleteCase::SiblingBlackChildrenBlack,
+    DeleteCase::NearNephewRed,
+    DeleteCase::FarNephewRed,
+];
+
+// How often each fixup case ran; deletion cases are split by the side of the doubly black node
+#[derive(Debug, Clone, Default, PartialEq)]
+struct CaseCoverage {
+    insert: [usize; 5],
+    delete: [[usize; 2]; 4],
+}
+
+impl CaseCoverage {
+    fn record(&mut self, event: &RebalanceEvent) {
+        match *event {
+            RebalanceEvent::InsertCase { case, .. } => self.insert[case as usize] += 1,
+            RebalanceEvent::DeleteCase { case, side, .. } => self.delete[case as usize][side as usize] += 1,
+            _ => {},
+        }
+    }
+
+    // (label, count) for every case, in a fixed order
+    fn rows(&self) -> Vec<(String, usize)> {
+        let mut rows = Vec::new();
+        for case in INSERT_CASES.iter() {
+            rows.push((format!("insert {}", insert_case_label(*case)), self.insert[*case as usize]));
+        }
+        for case in DELETE_CASES.iter() {
+            for side in [Side::Left, Side::Right].iter() {
+                let side_name = if *side == Side::Left { "left" } else { "right" };
+                rows.push((format!("delete {} ({})", delete_case_label(*case), side_name),
+                    self.delete[*case as usize][*side as usize]));
+            }
+        }
+        rows
+    }
+
+    fn missing(&self) -> Vec<String> {
+        self.rows().into_iter().filter(|(_, count)| *count == 0).map(|(label, _)| label).collect()
+    }
+}
+
+struct CoverageObserver(Rc<RefCell<CaseCoverage>>);
+
+impl RebalanceObserver for CoverageObserver {
+    fn on_event(&mut self, event: &RebalanceEvent) {
+        self.0.borrow_mut().record(event);
+    }
+}
+
+/// Property suite: `cases` random insert/delete sequences of `ops` steps on a
+/// `ShadowTree`, alternating the successor mode and the key range. After
+/// every step the red-black rules and the sorted contents are checked.
+/// Fails on the first broken property, or at the end if some fixup case of
+/// `red_black_tree_insertion_cover` / `red_black_tree_deletion_cover` never ran.
+fn run_properties(seed: u64, cases: usize, ops: usize) -> Result<CaseCoverage, String> {
+    let coverage = Rc::new(RefCell::new(CaseCoverage::default()));
+    for case in 0..cases {
+        let config = StressConfig {
+            seed: seed.wrapping_add(case as u64),
+            ops,
+            mix: [(60, 40, 0), (50, 50, 0), (70, 30, 0)][case % 3],
+            key_low: 0,
+            key_high: 8 + (case % 16) as i32 * 16,
+            successor: (case % 2) as i32,
+        };
+        let commands = generate_workload(&config);
+        let mut shadow = ShadowTree::with_successor(config.successor);
+        shadow.tree.set_paranoid(false);
+        shadow.tree.add_observer(Box::new(CoverageObserver(coverage.clone())));
+
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for command in &commands {
+                match *command {
+                    Command::Insert(x) => { shadow.insert(x); },
+                    Command::Delete(key) => { shadow.delete(key); },
+                    _ => {},
+                }
+                shadow.verify();
+            }
+        }));
+        std::panic::set_hook(hook);
+
+        if let Err(payload) = result {
+            let message = payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "unknown panic".to_string());
+            return Err(format!("{}\nreproduce with: rbtree stress --seed {} --ops {} --mix insert:{},delete:{} --key-range {}..{} --successor {}",
+                message, config.seed, config.ops, config.mix.0, config.mix.1, config.key_low, config.key_high, config.successor));
+        }
+    }
+
+    let coverage = coverage.borrow().clone();
+    let missing = coverage.missing();
+    if !missing.is_empty() {
+        return Err(format!("never exercised: {}", missing.join(", ")));
+    }
+    Ok(coverage)
+}
+
+/// Fuzz entry point for operation sequences, shaped for `fuzz_target!`.
+/// The first byte picks the successor mode, then every two bytes are one
+/// operation: the first modulo 4 chooses insert, delete, find or a range
+/// query as wide as the rest of that byte, and the second is the key (as
+/// `i8`, so keys collide often). Every step runs through a `ShadowTree` and
+/// is fully validated; any mismatch panics.
+fn fuzz_operations(data: &[u8]) {
+    let (successor, ops) = match data.split_first() {
+        Some((first, rest)) => ((first & 1) as i32, rest),
+        None => return,
+    };
+    let mut shadow = ShadowTree::with_successor(successor);
+    shadow.tree.set_paranoid(false);
+    for op in ops.chunks_exact(2) {
+        let key = op[1] as i8 as i32;
+        match op[0] % 4 {
+            0 => { shadow.insert(key); },
+            1 => { shadow.delete(key); },
+            2 => { shadow.contains(key); },
+            _ => { shadow.range(key, key + (op[0] >> 2) as i32); },
+        }
+        shadow.verify();
+    }
+}
+
+/// Fuzz entry point for the preorder parser. Arbitrary text must give an
+/// error, never a panic; whatever `parse_tree` accepts must be a valid tree
+/// that survives a round trip and further operations.
+fn fuzz_parser(data: &[u8]) {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    if let Ok(root) = parse_tree_unchecked(text) {
+        // Cây lỗi vẫn phải in ra được
+        let _ = validate(root.as_ref());
+        let _ = pretty(root.as_ref(), PrettyOptions::plain()).to_string();
+        let _ = to_dot(root.as_ref(), DotOptions::new());
+    }
+    if let Ok(tree) = RBTree::from_preorder(text) {
+        let shape = preorder_string(tree.root.as_ref());
+        let again = parse_tree(&shape).expect("parse_tree rejected its own output");
+        assert_eq!(preorder_string(again.as_ref()), shape, "round trip changed the tree");
+
+        let mut tree = tree;
+        tree.set_paranoid(true);
+        let keys = tree.keys();
+        let fresh = keys.iter().max().map_or(0, |max| max.saturating_add(1));
+        if !tree.contains(fresh) {
+            tree.insert(fresh);
+        }
+        for key in keys {
+            tree.delete(key);
+        }
+    }
+}
+
+// Hạt giống cho bộ sinh đầu vào: chuỗi thao tác ngẫu nhiên hoặc một cây hợp lệ
+fn fuzz_seed_input(target: &str, rng: &mut Rng) -> Vec<u8> {
+    if target == "parser" {
+        let mut tree = RBTree::new();
+        tree.set_paranoid(false);
+        for _ in 0..rng.below(20) {
+            let key = rng.between(-50, 50);
+            tree.insert(key);
+        }
+        preorder_string(tree.root.as_ref()).into_bytes()
+    } else {
+        (0..rng.below(400)).map(|_| rng.next() as u8).collect()
+    }
+}
+
+// Lật bit, chèn, xóa hoặc nhân đôi một đoạn byte
+fn fuzz_mutate(mut input: Vec<u8>, rng: &mut Rng) -> Vec<u8> {
+    const INTERESTING: &[u8] = b"-p:N0123456789 \n";
+    for _ in 0..1 + rng.below(4) {
+        let at = if input.is_empty() { 0 } else { rng.below(input.len() as u64) as usize };
+        match rng.below(4) {
+            0 if !input.is_empty() => input[at] ^= 1 << rng.below(8),
+            1 => input.insert(at, INTERESTING[rng.below(INTERESTING.len() as u64) as usize]),
+            2 if !input.is_empty() => { input.remove(at); },
+            _ if !input.is_empty() => {
+                let end = (at + 1 + rng.below(8) as usize).min(input.len());
+                let piece = input[at..end].to_vec();
+                input.splice(at..at, piece);
+            },
+            _ => input.push(rng.next() as u8),
+        }
+    }
+    input
+}
+
+/// `rbtree fuzz ops|parser [--seed N --iters M | FILE...]`: a small std-only
+/// fuzzer around the entry points above, or a replay of saved inputs.
+/// Crashing inputs are written to `fuzz-crash-<target>-<seed>-<iteration>`.
+///
+/// Descoped: the cargo-fuzz targets the fuzzing request asked for are not
+/// part of this tree. This file is built on its own with `rustc`, so there is
+/// no Cargo manifest for a `fuzz/` crate to depend on, and adding one is a
+/// packaging change of its own. Once the crate has a manifest, each target is
+/// a one-line `fuzz_target!(|data: &[u8]| fuzz_operations(data));` (and the
+/// same for `fuzz_parser`); until then this loop mutates seeds from
+/// `fuzz_seed_input`.
+fn run_fuzz(args: &[String]) -> i32 {
+    let target: fn(&[u8]) = match args.first().map(|a| a.as_str()) {
+        Some("ops") => fuzz_operations,
+        Some("parser") => fuzz_parser,
+        _ => {
+            eprintln!("usage: rbtree fuzz ops|parser [--seed N --iters M | FILE...]");
+            return 2;
+        },
+    };
+    let name = args[0].clone();
+    let mut seed = 1u64;
+    let mut iterations = 100_000u64;
+    let mut files = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let number = |value: Option<&String>| value.and_then(|v| v.parse::<u64>().ok());
+        match arg.as_str() {
+            "--seed" => seed = match number(rest.next()) { Some(n) => n, None => return 2 },
+            "--iters" => iterations = match number(rest.next()) { Some(n) => n, None => return 2 },
+            file => files.push(file.to_string()),
+        }
+    }
+
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let run = |input: &[u8]| std::panic::catch_unwind(|| target(input)).map_err(|payload| {
+        payload.downcast_ref::<String>().cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "unknown panic".to_string())
+    });
+
+    let mut code = 0;
+    if !files.is_empty() {
+        for file in &files {
+            match std::fs::read(file).map_err(|e| e.to_string()).and_then(|input| run(&input)) {
+                Ok(()) => println!("{}: ok", file),
+                Err(message) => {
+                    println!("{}: {}", file, message);
+                    code = 1;
+                },
+            }
+        }
+    } else {
+        let mut rng = Rng(seed);
+        for iteration in 0..iterations {
+            let mut input = fuzz_seed_input(&name, &mut rng);
+            if rng.below(4) != 0 {
+                input = fuzz_mutate(input, &mut rng);
+            }
+            if let Err(message) = run(&input) {
+                let path = format!("fuzz-crash-{}-{}-{}", name, seed, iteration);
+                std::fs::write(&path, &input).ok();
+                println!("iteration {}: {}\ninput saved to {}", iteration, message, path);
+                code = 1;
+                break;
+            }
+        }
+        if code == 0 {
+            println!("ok: {} inputs, seed {}", iterations, seed);
+        }
+    }
+    std::panic::set_hook(hook);
+    code
+}
+
+// Nanoseconds per operation for `ops` operations done by `f`
+fn time_per_op<F: FnOnce()>(ops: usize, f: F) -> f64 {
+    let start = std::time::Instant::now();
+    f();
+    start.elapsed().as_nanos() as f64 / ops.max(1) as f64
+}
+
+/// One benchmark row: nanoseconds per operation for this tree, `BTreeMap`
+/// and `HashMap` (`None` where the structure has no such operation).
+struct BenchRow {
+    workload: &'static str,
+    size: usize,
+    rbtree: f64,
+    btree: f64,
+    hash: Option<f64>,
+}
+
+/// Runs every workload at `size` elements. Keys are the even numbers
+/// `0, 2, .., 2 * (size - 1)` so odd keys always miss; random orders come
+/// from `Rng(seed)`, so a seed gives the same numbers of operations and keys
+/// on every run.
+fn bench_size(size: usize, seed: u64) -> Vec<BenchRow> {
+    use std::collections::{BTreeMap, HashMap};
+    use std::hint::black_box;
+
+    let mut rng = Rng(seed);
+    let sequential: Vec<i32> = (0..size as i32).map(|i| i * 2).collect();
+    let mut shuffled = sequential.clone();
+    for i in (1..shuffled.len()).rev() {
+        shuffled.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let lookups = size.min(1_000_000);
+    let hits: Vec<i32> = (0..lookups).map(|_| shuffled[rng.below(size as u64) as usize]).collect();
+    let misses: Vec<i32> = hits.iter().map(|k| k + 1).collect();
+    // 1000 ranges, each spanning about 1% of the keys
+    let width = (size / 100).max(1) as i32 * 2;
+    let ranges: Vec<i32> = (0..1000).map(|_| rng.between(0, (2 * size as i32 - width).max(0))).collect();
+    let new_tree = || {
+        let mut tree = RBTree::new();
+        tree.set_paranoid(false);
+        tree
+    };
+    let mut rows = Vec::new();
+
+    {
+        let mut tree = new_tree();
+        let mut btree = BTreeMap::new();
+        let mut hash = HashMap::new();
+        rows.push(BenchRow {
+            workload: "sequential insert",
+            size,
+            rbtree: time_per_op(size, || sequential.iter().for_each(|&k| { tree.insert(k); })),
+            btree: time_per_op(size, || sequential.iter().for_each(|&k| { btree.insert(k, ()); })),
+            hash: Some(time_per_op(size, || sequential.iter().for_each(|&k| { hash.insert(k, ()); }))),
+        });
+    }
+
+    let mut tree = new_tree();
+    let mut btree = BTreeMap::new();
+    let mut hash = HashMap::new();
+    rows.push(BenchRow {
+        workload: "random insert",
+        size,
+        rbtree: time_per_op(size, || shuffled.iter().for_each(|&k| { tree.insert(k); })),
+        btree: time_per_op(size, || shuffled.iter().for_each(|&k| { btree.insert(k, ()); })),
+        hash: Some(time_per_op(size, || shuffled.iter().for_each(|&k| { hash.insert(k, ()); }))),
+    });
+    for (workload, keys) in [("lookup hit", &hits), ("lookup miss", &misses)].iter() {
+        rows.push(BenchRow {
+            workload,
+            size,
+            rbtree: time_per_op(lookups, || keys.iter().for_each(|&k| { black_box(tree.contains(k)); })),
+            btree: time_per_op(lookups, || keys.iter().for_each(|&k| { black_box(btree.contains_key(&k)); })),
+            hash: Some(time_per_op(lookups, || keys.iter().for_each(|&k| { black_box(hash.contains_key(&k)); }))),
+        });
+    }
+    rows.push(BenchRow {
+        workload: "range scan (per range)",
+        size,
+        rbtree: time_per_op(ranges.len(), || ranges.iter().for_each(|&low| { black_box(tree.range(low, low + width - 1).len()); })),
+        btree: time_per_op(ranges.len(), || ranges.iter().for_each(|&low| { black_box(btree.range(low..low + width).count()); })),
+        hash: None,
+    });
+    rows.push(BenchRow {
+        workload: "iteration (per key)",
+        size,
+        rbtree: time_per_op(size, || { black_box(tree.keys().iter().sum::<i32>()); }),
+        btree: time_per_op(size, || { black_box(btree.keys().sum::<i32>()); }),
+        hash: Some(time_per_op(size, || { black_box(hash.keys().sum::<i32>()); })),
+    });
+    rows.push(BenchRow {
+        workload: "random delete",
+        size,
+        rbtree: time_per_op(size, || shuffled.iter().rev().for_each(|&k| { tree.delete(k); })),
+        btree: time_per_op(size, || shuffled.iter().rev().for_each(|&k| { btree.remove(&k); })),
+        hash: Some(time_per_op(size, || shuffled.iter().rev().for_each(|&k| { hash.remove(&k); }))),
+    });
+    rows
+}
+
+/// `rbtree bench [--sizes 1000,100000,10000000] [--seed N]`. Build with
+/// optimizations (`rustc -O`) or the numbers mean little.
+fn run_bench(args: &[String]) -> i32 {
+    let mut sizes = vec![1_000, 100_000, 10_000_000];
+    let mut seed = 1;
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next();
+        let parsed = match flag.as_str() {
+            "--sizes" => value.and_then(|v| v.split(',').map(|n| n.parse().ok()).collect::<Option<Vec<usize>>>())
+                .filter(|s| !s.is_empty() && s.iter().all(|&n| n > 0 && n <= i32::MAX as usize / 2))
+                .map(|s| sizes = s),
+            "--seed" => value.and_then(|v| v.parse().ok()).map(|n| seed = n),
+            _ => None,
+        };
+        if parsed.is_none() {
+            eprintln!("usage: rbtree bench [--sizes N,N,..] [--seed N]");
+            return 2;
+        }
+    }
+    if cfg!(debug_assertions) {
+        eprintln!("warning: debug build, numbers are not representative");
+    }
+
+    println!("{:<24} {:>10} {:>12} {:>12} {:>12}", "workload", "size", "rbtree ns", "BTreeMap ns", "HashMap ns");
+    for size in sizes {
+        for row in bench_size(size, seed) {
+            println!("{:<24} {:>10} {:>12.1} {:>12.1} {:>12}", row.workload, row.size, row.rbtree, row.btree,
+                row.hash.map_or("-".to_string(), |ns| format!("{:.1}", ns)));
+        }
+    }
+    0
+}
+
+// Operations given on the command line in the script syntax (`+50 -30`)
+fn operation_args(args: &[String]) -> Result<Vec<Operation>, String> {
+    args.iter().map(|arg| match Command::parse(arg)? {
+        Command::Insert(x) => Ok(Operation::Insert(x)),
+        Command::Delete(key) => Ok(Operation::Delete(key)),
+        _ => Err(format!("expected +N or -N, got {:?}", arg)),
+    }).collect()
+}
+
+// Reads FILE, or stdin when it is missing or `-`
+fn read_input(file: Option<&String>) -> std::io::Result<String> {
+    match file.map(|f| f.as_str()) {
+        None | Some("-") => {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut text).map(|_| text)
+        },
+        Some(file) => std::fs::read_to_string(file),
+    }
+}
+
+// Reads `--name N` pairs for the given flag names over the defaults; exits with 2 on bad input
+fn numeric_flags<const N: usize>(args: &[String], names: [&str; N], mut values: [u64; N]) -> [u64; N] {
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let slot = match names.iter().position(|name| name == flag) {
+            Some(slot) => slot,
+            None => {
+                eprintln!("unknown option {:?}", flag);
+                exit(2);
+            },
+        };
+        match rest.next().and_then(|v| v.parse().ok()) {
+            Some(value) => values[slot] = value,
+            None => {
+                eprintln!("{} needs a number", flag);
+                exit(2);
+            },
+        }
+    }
+    values
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("repl") => return run_repl(),
+        Some("run") => match read_input(args.get(2)) {
+            Ok(text) => exit(run_script(&text)),
+            Err(e) => {
+                eprintln!("cannot read script: {}", e);
+                exit(2);
+            },
+        },
+        Some("journal") => match operation_args(&args[2..]) {
+            Ok(ops) => {
+                print!("{}", record_journal(&ops, 0));
+                exit(0);
+            },
+            Err(message) => {
+                eprintln!("{}", message);
+                exit(2);
+            },
+        },
+        Some("animate") => match operation_args(&args[2..]) {
+            Ok(ops) => {
+                print!("{}", animation_html(&ops, 0));
+                exit(0);
+            },
+            Err(message) => {
+                eprintln!("{}", message);
+                exit(2);
+            },
+        },
+        Some("replay") => {
+            let journal = read_input(args.get(2)).map_err(|e| format!("cannot read journal: {}", e))
+                .and_then(|text| Journal::parse(&text));
+            match journal {
+                Ok(journal) => match replay(&journal) {
+                    Ok(_) => {
+                        println!("ok: {} steps replayed", journal.entries.len());
+                        exit(0);
+                    },
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        exit(1);
+                    },
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    exit(2);
+                },
+            }
+        },
+        Some("stress") => match StressConfig::parse(&args[2..]) {
+            Ok(config) => exit(run_stress(&config)),
+            Err(message) => {
+                eprintln!("{}", message);
+                exit(2);
+            },
+        },
+        Some("fuzz") => exit(run_fuzz(&args[2..])),
+        Some("bench") => exit(run_bench(&args[2..])),
+        Some("linearizability") => {
+            let settings = numeric_flags(&args[2..], ["--threads", "--ops", "--keys", "--seed"], [8, 2_000, 16, 1]);
+            let keys = settings[2].clamp(1, i32::MAX as u64) as i32;
+            if let Err(message) = linearizability_run(settings[0] as usize, settings[1] as usize, keys, settings[3]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("sharded") => {
+            let settings = numeric_flags(&args[2..], ["--threads", "--ops", "--seed"], [8, 20_000, 1]);
+            if let Err(message) = sharded_stress(settings[0] as usize, settings[1] as usize, settings[2]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("cow") => {
+            let settings = numeric_flags(&args[2..], ["--readers", "--ops", "--seed"], [4, 20_000, 1]);
+            if let Err(message) = cow_stress(settings[0] as usize, settings[1] as usize, settings[2]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("undo") => {
+            let settings = numeric_flags(&args[2..], ["--ops", "--seed"], [20_000, 1]);
+            if let Err(message) = undo_check(settings[0] as usize, settings[1]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("history") => {
+            let settings = numeric_flags(&args[2..], ["--ops", "--seed"], [5_000, 1]);
+            if let Err(message) = history_check(settings[0] as usize, settings[1]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("sync-stress") => {
+            let settings = numeric_flags(&args[2..], ["--readers", "--ops", "--seed"], [4, 100_000, 1]);
+            if let Err(message) = sync_stress(settings[0] as usize, settings[1] as usize, settings[2]) {
+                eprintln!("{}", message);
+                exit(1);
+            }
+            exit(0);
+        },
+        Some("properties") => {
+            let settings = numeric_flags(&args[2..], ["--seed", "--cases", "--ops"], [1, 200, 500]);
+            match run_properties(settings[0], settings[1] as usize, settings[2] as usize) {
+                Ok(coverage) => {
+                    for (label, count) in coverage.rows() {
+                        println!("{:<42} {}", label, count);
+                    }
+                    println!("ok: {} sequences, every fixup case exercised", settings[1]);
+                    exit(0);
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    exit(1);
+                },
+            }
+        },
+        Some(other) => {
+            eprintln!("unknown command {:?}, usage: rbtree [repl | run [FILE] | journal OPS.. | replay [FILE] | animate OPS.. | stress [OPTIONS] | properties [OPTIONS] | fuzz TARGET | bench [OPTIONS] | sync-stress [OPTIONS] | linearizability [OPTIONS] | sharded [OPTIONS] | cow [OPTIONS] | history [OPTIONS] | undo [OPTIONS]]", other);
+            exit(2);
+        },
+        None => {},
+    }
+
+    std::env::set_var("RUST_BACKTRACE", "full");
+    println!("\n==========================Kiem thu thu cong:=================================\n");
+
+    let mut tree = RBTree::new();
+    tree.set_paranoid(true);
+
+    let test_values = vec![50, 30, 55, 60, 62, 53, 35, 37, 31, 25, 10, 20, 23, 15, 13, 99, 1092];
+
+    for &value in &test_values {
+        tree.insert(value); 
+        let is_valid = check_red_black_tree(tree.root.as_ref()); 
+        println!("\nKTdoden = {}", is_valid);
+        preorder(tree.root.as_ref()); 
+        println!("\n");
+    }
+    for &value in &test_values {
+        // print!("{}", value);
+        tree.delete(value); 
+        let is_valid = check_red_black_tree(tree.root.as_ref()); 
+        println!("\nKTdoden = {}", is_valid);
+        preorder(tree.root.as_ref()); 
+        println!("\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shadow_tree_agrees_with_btreemap() {
+        for successor in 0..2 {
+            let mut rng = Rng(successor as u64 + 1);
+            let mut shadow = ShadowTree::with_successor(successor);
+            for _ in 0..3_000 {
+                let key = rng.between(0, 299);
+                match rng.below(3) {
+                    0 => { shadow.insert(key); },
+                    1 => { shadow.delete(key); },
+                    _ => { shadow.contains(key); },
+                }
             }
+            shadow.verify();
+            shadow.range(100, 200);
+            shadow.range(200, 100);
+            assert_eq!(shadow.tree.len(), shadow.tree.keys().len());
         }
     }
-    root.cloned() 
-}
 
+    #[test]
+    fn shadow_tree_reports_duplicates_and_missing_keys() {
+        let mut shadow = ShadowTree::new();
+        assert!(shadow.insert(5));
+        assert!(!shadow.insert(5));
+        assert!(!shadow.delete(7));
+        assert!(shadow.delete(5));
+        assert!(!shadow.delete(5));
+        shadow.verify();
+    }
 
-fn rotate_on_parent(
-    root: &mut Option<NodeRef>,
-    newnode: Option<NodeRef>,
-    s: &mut Option<NodeRef>,
-    lor: i32,
-    debug: i32,
-) -> Option<NodeRef> {
-    // let parent = newnode.as_ref().and_then(|node| node.borrow().parent.clone());
-    let parent = newnode.as_ref().unwrap().borrow().parent.clone();
-    let grandparent = parent.as_ref().unwrap().borrow().parent.clone();
+    #[test]
+    fn properties_exercise_every_fixup_case() {
+        let coverage = run_properties(1, 60, 300).unwrap_or_else(|message| panic!("{}", message));
+        assert!(coverage.missing().is_empty());
+    }
 
-    if let Some(grandparent_node) = &grandparent {
+    #[test]
+    fn properties_fail_when_a_case_is_never_hit() {
+        let message = run_properties(1, 1, 3).unwrap_err();
+        assert!(message.starts_with("never exercised: "), "{}", message);
+    }
 
-        let mut grandparent_borrow = grandparent_node.borrow_mut();
+    #[test]
+    fn pretty_draws_both_layouts() {
+        let tree = RBTree::from_preorder("20-0-p:N 10-1-p:20 30-1-p:20").unwrap();
+        let options = PrettyOptions { black_height: true, ..PrettyOptions::plain() };
+        assert_eq!(pretty(tree.root.as_ref(), options).to_string(), "    ┌── 30R[0]\n20B[1]\n    └── 10R[0]\n");
+        let top_down = PrettyOptions { layout: Layout::TopDown, ..PrettyOptions::plain() };
+        assert_eq!(pretty(tree.root.as_ref(), top_down).to_string(), "    20B\n ┌───┴───┐\n10R     30R\n");
+        assert_eq!(Command::parse("show top"), Ok(Command::Show(Layout::TopDown)));
+        assert_eq!(Command::parse("show"), Ok(Command::Show(Layout::Sideways)));
+    }
 
-        if Rc::ptr_eq(&grandparent_borrow.left.as_ref().unwrap(), &parent.as_ref().unwrap()) {
-            grandparent_borrow.left = s.clone();
-        } 
-        else if Rc::ptr_eq(&grandparent_borrow.right.as_ref().unwrap(), &parent.as_ref().unwrap()) {
-            grandparent_borrow.right = s.clone();
+    #[test]
+    fn tidy_layout_keeps_nodes_apart_and_in_order() {
+        let mut tree = RBTree::new();
+        tree.set_paranoid(false);
+        let mut rng = Rng(3);
+        for _ in 0..2_000 {
+            tree.insert(rng.between(0, 9_999));
         }
-    } 
-    else {
-        if debug > 0 {
-            println!("grandparent is NULL");
+        let nodes = tidy_tree(tree.root.as_ref().unwrap());
+        assert_eq!(nodes.len(), tree.len());
+        let depth = nodes.iter().map(|n| n.depth).max().unwrap();
+        for level in 0..=depth {
+            let mut row: Vec<&TidyNode> = nodes.iter().filter(|n| n.depth == level).collect();
+            row.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+            for pair in row.windows(2) {
+                assert!(pair[0].value < pair[1].value, "level {}: {} drawn left of {}", level, pair[1].value, pair[0].value);
+                assert!(pair[1].x - pair[0].x >= TIDY_SEPARATION - 1e-9,
+                    "level {}: {} and {} overlap", level, pair[0].value, pair[1].value);
+            }
+        }
+        for node in &nodes {
+            assert!(node.left.is_none_or(|l| nodes[l].x < node.x) && node.right.is_none_or(|r| nodes[r].x > node.x));
         }
-        *root = s.clone();
     }
 
-    if let (Some(parent_node), Some(s_node)) = (parent.as_ref(), s.as_ref()) {
-        if lor != 0 {
-            parent_node.borrow_mut().right = s_node.borrow().left.clone();
-            if let Some(left_node) = s_node.borrow().left.as_ref() {
-                left_node.borrow_mut().parent = parent.clone();
-            }
-            s_node.borrow_mut().left = parent.clone();
-        } 
-        else {
-            parent_node.borrow_mut().left = s_node.borrow().right.clone();
-            if let Some(right_node) = s_node.borrow().right.as_ref() {
-                right_node.borrow_mut().parent = parent.clone();
-            }
-            s_node.borrow_mut().right = parent.clone();
+    #[test]
+    fn repl_svg_command_writes_every_node() {
+        let mut session = Session::new();
+        for x in [50, 30, 70, 20, 40, 60, 80, 10] {
+            session.execute(Command::Insert(x)).unwrap();
         }
+        let file = std::env::temp_dir().join(format!("rbtree-test-{}.svg", std::process::id()));
+        let file_name = file.to_str().unwrap().to_string();
+        session.execute(Command::parse(&format!("svg {}", file_name)).unwrap()).unwrap();
+        let svg = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).ok();
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<circle").count(), 8);
+        assert_eq!(svg.matches("<line").count(), 7);
     }
 
-    if let Some(s_node) = s.as_ref() {
-        s_node.borrow_mut().parent = grandparent.clone();
+    #[test]
+    fn animation_labels_every_step_and_stays_offline() {
+        let ops = operation_args(&["+20", "+10", "+5", "+5", "-7", "+30", "-20"].map(String::from)).unwrap();
+        let html = animation_html(&ops, 0);
+        assert!(html.starts_with("<!DOCTYPE html>") && html.ends_with("</html>\n"));
+        assert!(!html.contains(" src=") && !html.contains("<link") && !html.contains("href="));
+        assert_eq!(html.matches("\"label\":\"done\"").count(), 5);
+        assert_eq!(html.matches("\"label\":\"before\"").count(), 5);
+        for label in ["LL: node 5, parent 10, grandparent 20", "skipped, key already present", "skipped, key not found"] {
+            assert!(html.contains(label), "missing frame {:?}", label);
+        }
+        // frames are JSON strings, so their markup is escaped inside the script
+        assert_eq!(html.matches("\\u003csvg ").count(), html.matches("\"svg\":").count());
+        assert!(!html.contains("</svg>"));
     }
-    if let Some(parent_node) = parent.as_ref() {
-        parent_node.borrow_mut().parent = s.clone();
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_are_counted_per_tree_and_reset() {
+        let mut tree = RBTree::new();
+        let mut other = RBTree::new();
+        for x in [1, 2, 3] {
+            tree.insert(x);
+        }
+        other.insert(10);
+        // 0 + 1 + 2 comparisons on the way down, then one RR rotation
+        assert_eq!(tree.stats().comparisons, 3);
+        assert_eq!((tree.stats().single_rotations, tree.stats().double_rotations), (1, 0));
+        assert!(tree.contains(3));
+        assert_eq!(tree.stats().comparisons, 5);
+        assert_eq!(other.stats().comparisons, 0);
+
+        let mut session = Session::new();
+        for line in ["+1", "+2", "+3", "?3"] {
+            session.execute(Command::parse(line).unwrap()).unwrap();
+        }
+        let report = session.execute(Command::parse("stats reset").unwrap()).unwrap();
+        assert!(report.starts_with("comparisons       5\n"), "{}", report);
+        assert_eq!(session.tree.tree().stats(), OpStats::default());
     }
 
-    std::mem::take(root)
-}
+    #[cfg(not(feature = "stats"))]
+    #[test]
+    fn stats_command_explains_the_missing_feature() {
+        let error = Session::new().execute(Command::Stats { reset: false }).unwrap_err();
+        assert!(error.contains("feature=\"stats\""), "{}", error);
+    }
 
-fn red_black_tree_deletion_cover(
-    mut root: Option<NodeRef>,
-    newnode: Option<NodeRef>,
-    deleted_node_color: Color,
-    lor: i32,
-    debug: i32,
-) -> Option<NodeRef> {
-    if newnode.is_none() || root.is_none(){
+    fn journal_ops() -> Vec<Operation> {
+        [50, 30, 55, 60, 62, 53, 35, 37, 31, 25, 10, 20].iter().map(|&x| Operation::Insert(x))
+            .chain([30, 60, 10, 99, 50].iter().map(|&x| Operation::Delete(x)))
+            .collect()
+    }
 
-        return root;
+    #[test]
+    fn journal_round_trips_through_text_and_replays() {
+        for successor in 0..2 {
+            let journal = record_journal(&journal_ops(), successor);
+            let parsed = Journal::parse(&journal.to_string()).unwrap();
+            assert_eq!(parsed, journal);
+            let tree = replay(&parsed).unwrap_or_else(|error| panic!("{}", error));
+            assert_eq!(normalize_shape(&preorder_string(tree.root.as_ref())), journal.entries.last().unwrap().shape);
+        }
     }
-    if deleted_node_color == Color::Red{
-        return root;
+
+    #[test]
+    fn replay_reports_the_step_that_diverged() {
+        let mut journal = record_journal(&journal_ops(), 0);
+        journal.successor = 1;
+        let first_difference = journal.entries.iter().position(|entry| entry.op == Operation::Delete(30)).unwrap();
+        match replay(&journal) {
+            Err(ReplayError::Diverged { step, .. }) => assert!(step >= first_difference, "step {}", step),
+            other => panic!("expected a divergence, got {:?}", other.map(|tree| tree.keys())),
+        }
+
+        let mut journal = record_journal(&journal_ops(), 0);
+        journal.entries[3].events.pop();
+        let error = replay(&journal).err().unwrap();
+        assert!(error.to_string().starts_with("step 3 (insert 60) diverged"), "{}", error);
     }
-    // print!("{}",newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color as i32);
-    if Rc::ptr_eq(&newnode.as_ref().unwrap(), &root.as_ref().unwrap()) || newnode.as_ref().unwrap().borrow().color == Color::Red{
-        newnode.as_ref().unwrap().borrow_mut().color = Color::Black;
-        // print!("Lan 1");
-        return root;
+
+    #[test]
+    fn sync_stress_keeps_readers_and_writer_consistent() {
+        sync_stress(2, 2_000, 7).unwrap();
     }
-    else {
-        // Loi nam trong doan nay
-        let mut s : Option<NodeRef> = None;
-        let mut cs = 0;
-        if newnode.as_ref().unwrap().borrow().value == i32::MAX{
 
-            if lor == 1{
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().right.clone();
-                cs = 1;
-            }
-            else if lor == 2{
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().left.clone();
-                cs = 2;
-            }
-            else {
-                cs -= 1;
-            }
+    #[test]
+    fn slot_and_snapshot_validators_catch_broken_trees() {
+        let mut tree = SyncRBTree::new();
+        let mut snapshot = Snapshot::default();
+        for k in [20, 10, 30, 5] {
+            tree.insert(k);
+            snapshot = snapshot.inserted(k).unwrap();
         }
-        else {
-            if Rc::ptr_eq(&newnode.as_ref().unwrap(), &newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.as_ref().unwrap()){
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().right.clone();
-                cs = 3;
-            }
-            else if Rc::ptr_eq(&newnode.as_ref().unwrap(), &newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().right.as_ref().unwrap()){
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.clone();
-                cs = 4;
-            }
-            else {
-                cs -= 2;
+        assert_eq!(tree.validate(), Ok(2));
+        assert_eq!(snapshot.validate(), Ok(2));
+
+        let ten = tree.find_slot(10).unwrap();
+        tree.set_color(ten, Color::Red);
+        assert_eq!(tree.validate().unwrap_err().rule, Rule::RedRed { parent: 10, child: 5 });
+        tree.set_color(ten, Color::Black);
+        let five = tree.find_slot(5).unwrap();
+        tree.set_parent(five, tree.find_slot(20));
+        assert_eq!(tree.validate().unwrap_err().rule, Rule::BrokenParentLink { expected: Some(10), found: Some(20) });
+        tree.set_parent(five, Some(ten));
+        tree.set_left(five, tree.find_slot(20));
+        let cycle = tree.validate().unwrap_err();
+        assert_eq!(cycle.rule, Rule::Cycle);
+        assert_eq!(&cycle.path[..4], &[20, 10, 5, 20]);
+
+        let red_root = Snapshot { root: p_recolor(&snapshot.root, Color::Red), ..snapshot };
+        assert_eq!(red_root.validate().unwrap_err().rule, Rule::RedRoot);
+        let unordered = Snapshot { root: pnode(Color::Black, pnode(Color::Red, None, 40, None), 20, None), len: 2, version: 0 };
+        assert_eq!(unordered.validate().unwrap_err().rule, Rule::OrderViolation { low: None, high: Some(20) });
+    }
+
+    #[test]
+    fn concurrent_tree_histories_are_linearizable() {
+        linearizability_run(4, 1_000, 16, 3).unwrap();
+        linearizability_run(4, 500, 200, 4).unwrap();
+    }
+
+    #[test]
+    fn concurrent_tree_matches_a_set_on_one_thread() {
+        let tree = ConcurrentRBTree::new();
+        let mut oracle = std::collections::BTreeSet::new();
+        let mut rng = Rng(11);
+        for _ in 0..3_000 {
+            let key = rng.between(0, 300);
+            if rng.below(2) == 0 {
+                assert_eq!(tree.insert(key), oracle.insert(key), "insert {}", key);
+            } else {
+                assert_eq!(tree.delete(key), oracle.remove(&key), "delete {}", key);
             }
+            assert_eq!(tree.len(), oracle.len());
         }
-        if cs <= 0{
-            print!("Co loi nghiem trong xay ra cs = {}\n", cs);
-            exit(1);
-        }
-        if cs == 1 || cs == 3{
-            if s.as_ref().unwrap().borrow().color == Color::Red{
-                if debug > 0{
-                    print!("NDK-TH1 ");  
-                }
-                s.as_ref().unwrap().borrow_mut().color = Color::Black;
-                newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().color = Color::Red;
-                root = rotate_on_parent(&mut root, newnode.as_ref().cloned(), &mut s, 1, debug);
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().as_ref().unwrap().borrow().right.clone();
-                if debug > 0{
-                    print!("\nroot={}\n", root.as_ref().unwrap().borrow().value);
-                    preorder(root.as_ref());
-                    print!("\n");
-                }
-            }
-            if (s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black) && 
-                    (s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black){
-                if debug > 0{
-                    print!("NDK-TH2 ");
-                }
-                s.as_ref().unwrap().borrow_mut().color = Color::Red;
-                // print!("{}", newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value);
-                root = red_black_tree_deletion_cover(root, newnode.as_ref().unwrap().borrow().parent.clone(), deleted_node_color, lor, debug);
-            }
-            else {
-                if s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black{
-                    if debug > 0{
-                        print!("NDK-TH3");
-                    }
-                    s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow_mut().color = Color::Black;
-                    s.as_ref().unwrap().borrow_mut().color = Color::Red;
-                    let new_right = s.as_ref().unwrap().borrow().left.clone();
-                    newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().right = new_right.clone();
-                    new_right.as_ref().unwrap().borrow_mut().parent = newnode.as_ref().unwrap().borrow().parent.clone();
-                    s.as_ref().unwrap().borrow_mut().left = new_right.as_ref().unwrap().borrow().right.clone();
-                    if let Some(new_right_right_ref) = &new_right.as_ref().unwrap().borrow().right.as_ref(){
-                        new_right_right_ref.borrow_mut().parent = s.clone();
-                    };
-                    new_right.as_ref().unwrap().borrow_mut().right = s.clone();
-                    s.as_ref().unwrap().borrow_mut().parent = new_right.clone();
-                    s = new_right.clone();
-                    if debug > 0{
-                        print!("\nroot={}\n", root.as_ref().unwrap().borrow().value);
-                        preorder(root.as_ref());
-                        print!("\n");
-                    }
-                }
+        tree.validate().unwrap();
+        assert!(tree.keys().iter().eq(oracle.iter()));
+        assert_eq!(tree.range(100, 200), oracle.range(100..=200).copied().collect::<Vec<_>>());
+    }
 
-                if debug > 0 {
-                    print!("NDK-TH4\n");
-                }
-                s.as_ref().unwrap().borrow_mut().color = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color;
-                newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().color = Color::Black;
-                s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow_mut().color = Color::Black;
+    #[test]
+    fn sharded_map_never_starts_more_shards_than_keys() {
+        let map = ShardedRBTreeMap::new(10, 0, 3, 8);
+        let lows: Vec<i32> = map.shard_sizes().iter().map(|&(low, _)| low).collect();
+        assert_eq!(lows, vec![i32::MIN, 1, 2, 3]);
+        for key in -2..6 {
+            assert_eq!(map.insert(key, key * 10), None);
+        }
+        map.validate().unwrap();
+        assert_eq!(map.iter().collect::<Vec<_>>(), (-2..6).map(|k| (k, k * 10)).collect::<Vec<_>>());
+        assert_eq!(ShardedRBTreeMap::<()>::new(3, 5, 1, 8).shard_count(), 1);
+    }
 
-                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 1, debug);
-                if debug > 0{
-                    print!("\nroot={}\n",root.as_ref().unwrap().borrow().value);
-                    preorder(root.as_ref());
-                    print!("\n");
-                }
-            }
+    #[test]
+    fn sharded_map_keeps_values_through_splits_and_joins() {
+        let map = ShardedRBTreeMap::new(2, 0, 99, 8);
+        for key in 0..40 {
+            assert_eq!(map.insert(key, format!("v{}", key)), None);
         }
-        else{
-            if s.as_ref().unwrap().borrow().color == Color::Red{
-                if debug > 0{
-                    print!("DK-NDK-TH1 ");
-                }
-                s.as_ref().unwrap().borrow_mut().color = Color::Black;
-                newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().color = Color::Red;
-                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 0, debug);
-                s = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().left.clone();
-                if debug > 0{
-                    print!("\nroot={}\n",root.as_ref().unwrap().borrow().value);
-                    preorder(root.as_ref());
-                    print!("\n");
-                }
-            }
-            if (s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black) && 
-                    (s.as_ref().unwrap().borrow().right.is_none() || s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow().color == Color::Black){
-                if debug > 0{
-                    print!("DK-NDK-TH2 ");
-                }
-                s.as_ref().unwrap().borrow_mut().color = Color::Red;
-                root = red_black_tree_deletion_cover(root, newnode.as_ref().unwrap().borrow().parent.clone(), deleted_node_color, lor, debug);
-            }
-            else {
-                if s.as_ref().unwrap().borrow().left.is_none() || s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow().color == Color::Black{
-                    if debug > 0{
-                        print!("DK-NDK-TH3");
-                    }
-                    s.as_ref().unwrap().borrow().right.as_ref().unwrap().borrow_mut().color = Color::Black;
-                    s.as_ref().unwrap().borrow_mut().color = Color::Red;
-                    let new_left = s.as_ref().unwrap().borrow().right.clone();
-                    newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().left = new_left.clone();
-                    new_left.as_ref().unwrap().borrow_mut().parent = newnode.as_ref().unwrap().borrow().parent.clone();
-                    s.as_ref().unwrap().borrow_mut().right = new_left.as_ref().unwrap().borrow().right.clone();
-                    if let Some(new_left_left_ref) = &new_left.as_ref().unwrap().borrow().left.as_ref(){
-                        new_left_left_ref.borrow_mut().parent = s.clone();
-                    };
-                    new_left.as_ref().unwrap().borrow_mut().left = s.clone();
-                    s.as_ref().unwrap().borrow_mut().parent = new_left.clone();
-                    s = new_left.clone();
-                    if debug > 0{
-                        print!("\nroot={}\n", root.as_ref().unwrap().borrow().value);
-                        preorder(root.as_ref());
-                        print!("\n");
-                    }
-                }
+        assert!(map.shard_count() > 2);
+        assert_eq!(map.insert(7, "seven".to_string()), Some("v7".to_string()));
+        assert_eq!(map.get(7), Some("seven".to_string()));
+        assert_eq!(map.get(40), None);
+        assert_eq!(map.range(6, 8).collect::<Vec<_>>(),
+            vec![(6, "v6".to_string()), (7, "seven".to_string()), (8, "v8".to_string())]);
+        for key in 2..40 {
+            assert!(map.delete(key).is_some());
+        }
+        assert_eq!(map.delete(2), None);
+        map.validate().unwrap();
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(0, "v0".to_string()), (1, "v1".to_string())]);
+        sharded_stress(3, 3_000, 7).unwrap();
+    }
 
-                if debug > 0{
-                    print!("DK-NDK-TH4\n");
-                }
-                s.as_ref().unwrap().borrow_mut().color = newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().color;
-                newnode.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow_mut().color = Color::Black;
-                s.as_ref().unwrap().borrow().left.as_ref().unwrap().borrow_mut().color = Color::Black;
+    #[test]
+    fn cow_tree_frees_replaced_versions_once_no_reader_needs_them() {
+        let tree = CowRBTree::new();
+        let first = tree.latest();
+        tree.insert(1);
+        tree.insert(2);
+        assert_eq!(std::sync::Arc::strong_count(&first), 1);
+        assert!(first.keys().is_empty());
+        assert_eq!(tree.snapshot().keys(), vec![1, 2]);
+        cow_stress(2, 2_000, 5).unwrap();
+    }
 
-                root = rotate_on_parent(&mut root.clone(), newnode.clone(), &mut s.clone(), 0, debug);
-                if debug > 0{
-                    print!("\nroot={}\n",root.as_ref().unwrap().borrow().value);
-                    preorder(root.as_ref());
-                    print!("\n");
-                }
-            }
+    fn run_lines(session: &mut Session, lines: &[&str]) -> Vec<Result<String, String>> {
+        lines.iter().map(|line| session.execute(Command::parse(line).unwrap())).collect()
+    }
+
+    #[test]
+    fn repl_undo_redo_and_rollback_share_one_history() {
+        let mut session = Session::new();
+        run_lines(&mut session, &["+10", "+20", "checkpoint two", "+30", "-10"]);
+        assert_eq!(session.tree.tree().keys(), vec![20, 30]);
+        assert_eq!(run_lines(&mut session, &["undo", "undo", "redo"]),
+            vec![Ok("undid delete 10".to_string()), Ok("undid insert 30".to_string()), Ok("redid insert 30".to_string())]);
+        assert_eq!(session.tree.tree().keys(), vec![10, 20, 30]);
+        assert_eq!(run_lines(&mut session, &["rollback two"]), vec![Ok("rolled back to two (1 operations)".to_string())]);
+        assert_eq!(session.tree.tree().keys(), vec![10, 20]);
+
+        run_lines(&mut session, &["undo", "+40"]);
+        assert_eq!(run_lines(&mut session, &["redo", "rollback two", "rollback three"]), vec![
+            Err("nothing to redo".to_string()),
+            Err("checkpoint two was overwritten by later writes".to_string()),
+            Err("no checkpoint named three".to_string()),
+        ]);
+        assert!(run_lines(&mut session, &["check"])[0].is_ok());
+    }
+
+    #[test]
+    fn failed_undo_and_redo_keep_the_tree_and_the_entry() {
+        let mut tree = UndoableRBTree::new();
+        tree.tree_mut().set_paranoid(false);
+        for x in [10, 5, 15, 1, 20] {
+            tree.insert(x);
         }
-        if newnode.as_ref().unwrap().borrow().value == i32::MAX{
-            drop(newnode);
+        // Blacken the red leaf 1 behind the history's back
+        let one = tree.tree().find(1).unwrap();
+        one.borrow_mut().color = Color::Black;
+        let before = preorder_string(tree.tree().root.as_ref());
+        assert_eq!(tree.undo().unwrap_err().rule, Rule::BlackHeightMismatch);
+        assert!(tree.can_undo() && !tree.can_redo());
+        assert_eq!(preorder_string(tree.tree().root.as_ref()), before);
+        one.borrow_mut().color = Color::Red;
+        assert_eq!(tree.undo(), Ok(Some(Operation::Insert(20))));
+
+        one.borrow_mut().color = Color::Black;
+        let before = preorder_string(tree.tree().root.as_ref());
+        assert_eq!(tree.redo().unwrap_err().rule, Rule::BlackHeightMismatch);
+        assert!(tree.can_redo() && tree.can_undo());
+        assert_eq!(preorder_string(tree.tree().root.as_ref()), before);
+        one.borrow_mut().color = Color::Red;
+        assert_eq!(tree.redo(), Ok(Some(Operation::Insert(20))));
+        assert_eq!(tree.tree().keys(), vec![1, 5, 10, 15, 20]);
+    }
+
+    #[test]
+    fn repl_dot_draws_nil_leaves_parent_edges_and_the_search_path() {
+        let file = std::env::temp_dir().join(format!("rbtree-dot-{}.dot", std::process::id()));
+        let name = file.to_str().unwrap().to_string();
+        let mut session = Session::new();
+        run_lines(&mut session, &["+20", "+10", "+30", "+25"]);
+        assert_eq!(run_lines(&mut session, &[&format!("dot {} 25", name)]), vec![Ok(format!("wrote 4 nodes to {}", name))]);
+        let dot = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        // 4 nút thì có 5 lá NIL, mỗi lá là một hộp nhỏ
+        assert_eq!(dot.matches("[shape=box, width=0.15, height=0.15, label=\"\", fillcolor=black]").count(), 5);
+        assert!(dot.contains("\"30\":se -> nil3;"));
+        assert!(dot.contains("\"25\":se -> nil5;"));
+        assert_eq!(dot.matches("style=dashed").count(), 3);
+        for (child, parent) in [(10, 20), (30, 20), (25, 30)] {
+            assert!(dot.contains(&format!("\"{}\" -> \"{}\" [style=dashed", child, parent)), "{} -> {}", child, parent);
         }
-        return root;
+        assert!(dot.contains("\"20\" [fillcolor=black, penwidth=3, color=blue];"));
+        assert!(dot.contains("\"30\" [fillcolor=black, penwidth=3, color=blue];"));
+        assert!(dot.contains("\"25\" [fillcolor=red, penwidth=3, color=blue];"));
+        assert!(dot.contains("\"10\" [fillcolor=black];"));
+        assert!(dot.contains("\"20\":se -> \"30\" [penwidth=3, color=blue];"));
+        assert!(dot.contains("\"30\":sw -> \"25\" [penwidth=3, color=blue];"));
+        assert!(dot.contains("\"20\":sw -> \"10\";"));
     }
-    // return root;
-}
 
-fn delete(
-    root:&mut Option<NodeRef>,
-    key: i32,
-    debug: i32,
-    successor: i32,
-) -> Option<NodeRef> {
-    let mut newnode = SavedNodeInfo {saved_node: None, is_left_or_right_child: 0, deleted_node_color: Color::Black};
-    newnode.saved_node = None;
-    if debug > 0{
-        print!("\nnut se xoa: {} ", key);
+    #[test]
+    fn history_check_passes_under_the_test_harness() {
+        history_check(3_000, 11).unwrap();
     }
-    *root = delete_norm_bst(root.clone().as_ref(), None, key,&mut newnode, successor);
-    
-    if debug >0 {
-        if !newnode.saved_node.is_none(){
-            print!("\nnutthaythe: {} ", newnode.saved_node.as_ref().unwrap().borrow().value);
-            if !newnode.saved_node.as_ref().unwrap().borrow().parent.is_none(){
-                print!("nutchacua nutthaythe:{} \n", newnode.saved_node.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value);
-            }
-            else {
-                print!("nutchacua nutthaythe:NULL \n");
-            }
+
+    #[test]
+    fn gc_drops_old_versions_but_kept_ones_still_answer() {
+        let mut tree = VersionedRBTree::new();
+        for key in 1..=5 {
+            assert!(tree.insert(key));
         }
-        else {
-            print!("khong the xoa nut \n");
+        assert!(tree.delete(2));
+        assert_eq!((tree.oldest_version(), tree.latest_version()), (0, 6));
+        let early = tree.range_at(2, i32::MIN, i32::MAX).unwrap();
+
+        assert_eq!(tree.gc(3), 3);
+        assert_eq!(tree.version_count(), 4);
+        assert_eq!(tree.get_at(2, &1), Err(VersionError::Reclaimed { version: 2, oldest: 3 }));
+        assert_eq!(tree.get_at(7, &1), Err(VersionError::NotYetWritten { version: 7, latest: 6 }));
+        assert_eq!(tree.get_at(3, &3), Ok(Some(3)));
+        assert_eq!(tree.get_at(3, &4), Ok(None));
+        assert_eq!(tree.get_at(5, &2), Ok(Some(2)));
+        assert_eq!(tree.get_at(6, &2), Ok(None));
+        assert_eq!(tree.range_at(4, 2, 10).unwrap().collect::<Vec<_>>(), vec![2, 3, 4]);
+        // Bộ duyệt lấy trước khi gc vẫn giữ phiên bản của nó
+        assert_eq!(early.collect::<Vec<_>>(), vec![1, 2]);
+
+        assert_eq!(tree.gc(100), 3);
+        assert_eq!(tree.version_count(), 1);
+        assert_eq!(tree.range_at(6, i32::MIN, i32::MAX).unwrap().collect::<Vec<_>>(), vec![1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn vec_observer_records_insert_cases_recolors_and_rotations() {
+        use RebalanceEvent::*;
+        let mut root = None;
+        let mut log = Logger::off();
+        let mut steps = Vec::new();
+        for x in [1, 2, 3] {
+            let mut events: Vec<RebalanceEvent> = Vec::new();
+            assert!(insert(&mut root, x, &mut log, &mut events, &mut 0));
+            steps.push(events);
         }
+        assert_eq!(steps, vec![
+            vec![Recolor { key: 1, color: Color::Black }],
+            vec![],
+            // Nút chú không có nên phải tạo nút ảo trước khi chọn trường hợp
+            vec![
+                PhantomCreated { parent: Some(1) },
+                InsertCase { case: super::InsertCase::RR, node: 3, parent: 2, grandparent: 1 },
+                Recolor { key: 2, color: Color::Black },
+                Recolor { key: 1, color: Color::Red },
+                RotateLeft { pivot: 1, child: 2 },
+            ],
+        ]);
+        assert_eq!(preorder_string(root.as_ref()), "2-0-p:N 1-1-p:2 3-1-p:2 ");
     }
-    if debug > 0{
-        print!("\nroot={}\n", root.as_ref().unwrap().borrow().value);
-        preorder(root.as_ref());
-        print!("\n");
+
+    #[test]
+    fn vec_observer_records_the_phantom_and_case_of_a_black_leaf_delete() {
+        use RebalanceEvent::*;
+        let mut root = parse_tree("2-0-p:N 1-0-p:2 4-0-p:2 3-1-p:4 5-1-p:4").unwrap();
+        let mut events: Vec<RebalanceEvent> = Vec::new();
+        assert!(delete(&mut root, 1, &mut Logger::off(), 0, &mut events, &mut 0));
+        assert_eq!(events, vec![
+            PhantomCreated { parent: Some(2) },
+            DeleteCase { case: super::DeleteCase::FarNephewRed, side: Side::Left, parent: 2, sibling: 4 },
+            Recolor { key: 5, color: Color::Black },
+            RotateLeft { pivot: 2, child: 4 },
+        ]);
+        assert_eq!(preorder_string(root.as_ref()), "4-0-p:N 2-0-p:4 3-1-p:2 5-0-p:4 ");
+        assert_eq!(validate(root.as_ref()), Ok(2));
     }
 
-    if !newnode.saved_node.is_none(){
-        // print!("{}", newnode.saved_node.as_ref().unwrap().borrow().parent.as_ref().unwrap().borrow().value);
-        *root = red_black_tree_deletion_cover(root.as_ref().cloned(), newnode.saved_node, newnode.deleted_node_color, newnode.is_left_or_right_child, debug);
+    #[test]
+    fn checkpoints_survive_undo_and_redo_until_a_new_write_replaces_them() {
+        let mut tree = UndoableRBTree::new();
+        tree.insert(1);
+        tree.insert(2);
+        let two = tree.checkpoint();
+        tree.insert(3);
+        let three = tree.checkpoint();
+        tree.delete(1);
+
+        assert_eq!(tree.undo(), Ok(Some(Operation::Delete(1))));
+        assert_eq!(tree.undo(), Ok(Some(Operation::Insert(3))));
+        assert_eq!(tree.redo(), Ok(Some(Operation::Insert(3))));
+        assert_eq!(tree.rollback_to(two), Ok(Some(1)));
+        assert_eq!(tree.tree().keys(), vec![1, 2]);
+        assert_eq!(tree.rollback_to(three), Ok(Some(1)));
+        assert_eq!(tree.tree().keys(), vec![1, 2, 3]);
+        assert_eq!(tree.redo(), Ok(Some(Operation::Delete(1))));
+        assert_eq!(tree.rollback_to(two), Ok(Some(2)));
+
+        // Ghi mới sau khi đã lùi qua `three` làm nó hết hiệu lực, `two` thì không
+        tree.insert(4);
+        assert_eq!(tree.rollback_to(three), Ok(None));
+        assert_eq!(tree.tree().keys(), vec![1, 2, 4]);
+        assert_eq!(tree.redo(), Ok(None));
+        assert_eq!(tree.rollback_to(two), Ok(Some(1)));
+        assert_eq!(tree.tree().keys(), vec![1, 2]);
+        assert_eq!(tree.redo(), Ok(Some(Operation::Insert(4))));
+
+        undo_check(5_000, 3).unwrap();
     }
-    else {
-        print!("\nLoi, khong the xoa {}\n", key);
-        exit(1);
+
+    #[test]
+    fn parse_errors_point_at_the_offending_token() {
+        let at = |text: &str| parse_tree(text).map(|_| ()).unwrap_err();
+        assert_eq!(at("10-0-p:N\n  5-1-p:10 oops"), ParseError {
+            line: 2, column: 12, message: "expected value-color-p:parent, got \"oops\"".to_string() });
+        assert_eq!(at("10-0-p:N 5-1-p:7"), ParseError {
+            line: 1, column: 10, message: "parent 7 is not an ancestor on the current path".to_string() });
+        assert_eq!(at("10-0-p:N\n5-1-p:10\n15-1-p:10 5-0-p:15"), ParseError {
+            line: 3, column: 11, message: "duplicate value 5".to_string() });
+        // Lỗi luật đỏ-đen chỉ vào nút cha màu đỏ
+        let red_red = at("10-0-p:N\n5-1-p:10\n    2-1-p:5 15-0-p:10");
+        assert_eq!((red_red.line, red_red.column), (2, 1));
+        assert!(red_red.message.starts_with("red node 5 has red child 2"), "{}", red_red);
     }
-    return take(root);
-}
 
-fn main() {
-    std::env::set_var("RUST_BACKTRACE", "full");
-    println!("\n==========================Kiem thu thu cong:=================================\n");
+    #[test]
+    fn preorder_round_trips_through_the_parser() {
+        let mut tree = RBTree::new();
+        for x in [50, 30, 70, 20, 40, 60, 80, 35, 45, 65] {
+            tree.insert(x);
+        }
+        tree.delete(70);
+        let text = preorder_string(tree.root.as_ref());
+        let again = parse_tree(&text).unwrap();
+        assert_eq!(preorder_string(again.as_ref()), text);
+        assert_eq!(validate(again.as_ref()), validate(tree.root.as_ref()));
+        // Xuống dòng giữa các token không làm đổi cây
+        assert_eq!(preorder_string(parse_tree(&text.replace(' ', "\n")).unwrap().as_ref()), text);
+    }
 
-    let mut root: Option<NodeRef> = None;
+    #[test]
+    fn closure_sink_captures_trace_lines_with_their_levels() {
+        use LogLevel::*;
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        let mut tree = RBTree::new();
+        tree.set_logger(Logger::new(Dumps, Box::new(move |level: LogLevel, message: &str| {
+            sink.borrow_mut().push((level, message.to_string()));
+        })));
+        for x in [10, 20, 30] {
+            tree.insert(x);
+        }
+        tree.delete(10);
+        let expected = vec![
+            (Operations, "nutvuachen: 10"),
+            (Operations, "nutvuachen: 20"),
+            (Operations, "nutcha: 10"),
+            (Operations, "nutvuachen: 30"),
+            (Operations, "nutcha: 20"),
+            (Operations, "nutong: 10"),
+            (Cases, "node=30, cha=20, ong=10"),
+            (Cases, "make fantom"),
+            (Cases, "TH RR node=30, cha=20, ong=10"),
+            (Operations, "nut se xoa: 10"),
+            (Operations, "nutthaythe: 2147483647 nutchacua nutthaythe:20"),
+            (Dumps, "root=20 20-0-p:N 30-1-p:20 "),
+        ];
+        assert_eq!(*lines.borrow(), expected.iter().map(|&(l, m)| (l, m.to_string())).collect::<Vec<_>>());
 
-    let test_values = vec![50, 30, 55, 60, 62, 53, 35, 37, 31, 25, 10, 20, 23, 15, 13, 99, 1092];
+        // Ở mức Operations các dòng chi tiết hơn bị bỏ qua
+        lines.borrow_mut().clear();
+        let sink = lines.clone();
+        tree.set_logger(Logger::new(Operations, Box::new(move |level: LogLevel, message: &str| {
+            sink.borrow_mut().push((level, message.to_string()));
+        })));
+        tree.insert(10);
+        tree.insert(5);
+        assert!(!lines.borrow().is_empty());
+        assert!(lines.borrow().iter().all(|(level, _)| *level == Operations));
+    }
 
-    for &value in &test_values {
-        root = insert(root, value, 0); 
-        let is_valid = check_red_black_tree(root.as_ref()); 
-        println!("\nKTdoden = {}", is_valid);
-        preorder(root.as_ref()); 
-        println!("\n");
+    #[test]
+    fn validate_names_the_rule_and_path_on_a_corrupted_tree() {
+        // 20 đen ở gốc, 10 và 30 đen, 5 và 25 đỏ
+        let fresh = || {
+            let mut tree = RBTree::new();
+            for x in [20, 10, 30, 5, 25] {
+                tree.insert(x);
+            }
+            assert_eq!(validate(tree.root.as_ref()), Ok(2));
+            tree
+        };
+        let check = |tree: &RBTree, rule: Rule, path: Vec<i32>, sides: (i32, i32)| {
+            let violation = validate(tree.root.as_ref()).unwrap_err();
+            assert_eq!(violation, Violation { rule, path, left_black_height: sides.0, right_black_height: sides.1 });
+        };
+
+        let tree = fresh();
+        tree.find(20).unwrap().borrow_mut().color = Color::Red;
+        check(&tree, Rule::RedRoot, vec![20], (1, 1));
+
+        let tree = fresh();
+        tree.find(10).unwrap().borrow_mut().color = Color::Red;
+        check(&tree, Rule::RedRed { parent: 10, child: 5 }, vec![20, 10], (0, 0));
+
+        let tree = fresh();
+        tree.find(5).unwrap().borrow_mut().color = Color::Black;
+        check(&tree, Rule::BlackHeightMismatch, vec![20, 10], (1, 0));
+
+        let tree = fresh();
+        let ten = tree.find(10).unwrap();
+        tree.find(25).unwrap().borrow_mut().parent = Some(ten);
+        check(&tree, Rule::BrokenParentLink { expected: Some(30), found: Some(10) }, vec![20, 30, 25], (0, 0));
+
+        let tree = fresh();
+        tree.find(25).unwrap().borrow_mut().value = 15;
+        check(&tree, Rule::OrderViolation { low: Some(20), high: Some(30) }, vec![20, 30, 15], (0, 0));
     }
-    for &value in &test_values {
-        // print!("{}", value);
-        root = delete(&mut root, value, 0, 0); 
-        let is_valid = check_red_black_tree(root.as_ref()); 
-        println!("\nKTdoden = {}", is_valid);
-        preorder(root.as_ref()); 
-        println!("\n");
+
+    #[test]
+    #[should_panic(expected = "insert 40 broke the tree: black height mismatch at path [20, 10] (black height left=1, right=0)\n\
+        tree before insert 40: 20-0-p:N 10-0-p:20 5-0-p:10 30-0-p:20 25-1-p:30 \n\
+        tree after:\n        ┌── 40R\n")]
+    fn paranoid_mode_panics_with_the_op_the_violation_and_both_trees() {
+        let mut tree = RBTree::new();
+        tree.set_paranoid(true);
+        for x in [20, 10, 30, 5, 25] {
+            tree.insert(x);
+        }
+        // Sửa màu sau lưng cây; lần chèn kế tiếp phải phát hiện ra
+        tree.find(5).unwrap().borrow_mut().color = Color::Black;
+        tree.insert(40);
+    }
+
+    #[test]
+    fn run_script_exit_codes_follow_the_contract() {
+        assert_eq!(run_script("+50\n+30   # bình luận\n\n?30\ncheck\ndump\n"), 0);
+        assert_eq!(run_script(include_str!("scenarios/manual.txt")), 0);
+        // Lệnh sau `quit` không chạy nên không tính là lỗi
+        assert_eq!(run_script("+1\nquit\n+1\n"), 0);
+
+        assert_eq!(run_script("+50\n+50\n"), 1);
+        assert_eq!(run_script("+50\n-7\n?50\n"), 1);
+
+        assert_eq!(run_script("+50\nfrobnicate 3\n"), 2);
+        assert_eq!(run_script("+50\n+x\n+50\n"), 2);
+    }
+
+    #[test]
+    fn shrinker_cuts_a_workload_down_to_the_failing_pair() {
+        let config = StressConfig { seed: 5, ops: 400, key_low: 0, key_high: 99, ..StressConfig::new() };
+        let mut commands = generate_workload(&config);
+        commands.insert(120, Command::Insert(1_017));
+        commands.insert(300, Command::Insert(1_042));
+        // Chỉ lỗi khi có cả hai khóa, báo ở lệnh đến sau
+        let both = |commands: &[Command]| {
+            let at = |key| commands.iter().position(|c| *c == Command::Insert(key));
+            match (at(1_017), at(1_042)) {
+                (Some(a), Some(b)) => Err((a.max(b), "both keys".to_string())),
+                _ => Ok(()),
+            }
+        };
+        assert!(both(&commands).is_err());
+        assert_eq!(shrink_workload(commands, both), vec![Command::Insert(1_017), Command::Insert(1_042)]);
+        assert_eq!(shrink_workload(vec![Command::Insert(1), Command::Find(2)], both), vec![Command::Insert(1), Command::Find(2)]);
+    }
+
+    #[test]
+    fn shape_stats_of_small_known_trees() {
+        let full = parse_tree("20-0-p:N 10-0-p:20 5-1-p:10 30-0-p:20 25-1-p:30 40-1-p:30").unwrap();
+        let stats = shape_stats(full.as_ref());
+        assert_eq!((stats.nodes, stats.height, stats.black_height), (6, 3, Some(2)));
+        assert_eq!((stats.min_leaf_depth, stats.max_leaf_depth), (2, 2));
+        assert_eq!(stats.depth_histogram, vec![1, 2, 3]);
+        assert_eq!(stats.red_ratio, 0.5);
+        assert!((stats.height_bound_ratio - 3.0 / (2.0 * 7f64.log2())).abs() < 1e-12);
+
+        let lopsided = parse_tree("20-0-p:N 10-0-p:20 5-1-p:10 30-0-p:20").unwrap();
+        let stats = shape_stats(lopsided.as_ref());
+        assert_eq!((stats.nodes, stats.height, stats.black_height), (4, 3, Some(2)));
+        assert_eq!((stats.min_leaf_depth, stats.max_leaf_depth), (1, 2));
+        assert_eq!(stats.depth_histogram, vec![1, 2, 1]);
+        assert_eq!(stats.red_ratio, 0.25);
+        assert_eq!(stats.to_string().lines().last(), Some("depth histogram 0:1 1:2 2:1"));
+
+        // Cây sai luật vẫn đo được, chỉ mất chiều cao đen
+        let broken = parse_tree_unchecked("20-0-p:N 10-0-p:20").unwrap();
+        let stats = shape_stats(broken.as_ref());
+        assert_eq!((stats.nodes, stats.height, stats.black_height), (2, 2, None));
+        assert!(stats.to_string().contains("black height invalid"));
+        assert_eq!(shape_stats(None).nodes, 0);
     }
 }